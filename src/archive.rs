@@ -0,0 +1,330 @@
+//! Extracting a plugin binary from a prebuilt `.tar.gz` or `.zip` asset.
+//!
+//! Only the selected member is read out of the archive and it is written to a
+//! path chosen by the caller, never to a path taken from the archive. Entries
+//! with absolute paths or `..` components, symlinks and other non-regular
+//! files are never selected.
+
+use crate::error::Failure;
+use crate::schema::ArchiveFormat;
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use glob::{MatchOptions, Pattern};
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+/// Member selected when `member` is not set.
+const DEFAULT_MEMBER: &str = "*.so";
+
+impl ArchiveFormat {
+    /// Guess the format from the file name at the end of `url`.
+    pub fn from_url(url: &str) -> Option<Self> {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if path.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Extract the one member of `archive` matching `member` to `dst` and return its name.
+///
+/// `member` is a path or glob inside the archive. A pattern without `/`
+/// matches file names at any depth, so `libfoo.so` also finds `foo-1.0/libfoo.so`.
+pub fn extract(format: ArchiveFormat, archive: &Path, member: Option<&str>, dst: &Path) -> Result<String> {
+    let member = member.unwrap_or(DEFAULT_MEMBER);
+    let pattern = Pattern::new(member.trim_start_matches("./"))
+        .map_err(|e| Failure::Config(format!("invalid archive member pattern '{}': {}", member, e)))?;
+    let matches = |name: &str| matches(&pattern, name);
+
+    let file = fs::File::open(archive).with_context(|| format!("failed to open {}", archive.display()))?;
+
+    let found = match format {
+        ArchiveFormat::TarGz => extract_tar(file, &matches, dst)?,
+        ArchiveFormat::Zip => extract_zip(file, &matches, dst)?,
+    };
+
+    match found.as_slice() {
+        [name] => Ok(name.clone()),
+        [] => bail!(Failure::Fetch(format!("archive has no file matching '{}'", member))),
+        names => bail!(Failure::Fetch(format!(
+            "archive has more than one file matching '{}': {}",
+            member,
+            names.join(", ")
+        ))),
+    }
+}
+
+/// Copies the first matching entry to `dst` and returns the names of all matching entries.
+fn extract_tar(file: fs::File, matches: &dyn Fn(&str) -> bool, dst: &Path) -> Result<Vec<String>> {
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+    let mut found = vec![];
+
+    for entry in tar.entries().context("failed to read tar archive")? {
+        let mut entry = entry.context("failed to read tar archive")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().context("invalid path in tar archive")?;
+        let Some(name) = safe_name(&path) else {
+            log::debug!("skipping unsafe archive entry {}", path.display());
+            continue;
+        };
+        if !matches(&name) {
+            continue;
+        }
+
+        if found.is_empty() {
+            write_member(&mut entry, dst)?;
+        }
+        found.push(name);
+    }
+
+    Ok(found)
+}
+
+fn extract_zip(file: fs::File, matches: &dyn Fn(&str) -> bool, dst: &Path) -> Result<Vec<String>> {
+    let mut zip = zip::ZipArchive::new(file).context("failed to read zip archive")?;
+    let mut found = vec![];
+
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i).context("failed to read zip archive")?;
+        if !entry.is_file() || entry.is_symlink() {
+            continue;
+        }
+        // enclosed_name() would quietly make absolute names relative
+        let Some(name) = safe_name(Path::new(entry.name())) else {
+            log::debug!("skipping unsafe archive entry {}", entry.name());
+            continue;
+        };
+        if matches(&name) {
+            found.push((i, name));
+        }
+    }
+
+    if let Some((i, _)) = found.first() {
+        let mut entry = zip.by_index(*i).context("failed to read zip archive")?;
+        write_member(&mut entry, dst)?;
+    }
+
+    Ok(found.into_iter().map(|(_, name)| name).collect())
+}
+
+fn write_member(reader: &mut impl io::Read, dst: &Path) -> Result<()> {
+    let mut out = fs::File::create(dst).with_context(|| format!("failed to create {}", dst.display()))?;
+    io::copy(reader, &mut out).context("failed to extract archive member")?;
+    Ok(())
+}
+
+/// `path` as a relative `/` separated name, or None if it could point outside the archive.
+fn safe_name(path: &Path) -> Option<String> {
+    let mut parts = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+fn matches(pattern: &Pattern, name: &str) -> bool {
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+    if pattern.as_str().contains('/') {
+        pattern.matches_with(name, options)
+    } else {
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        pattern.matches_with(file_name, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use tar::EntryType;
+    use tempfile::TempDir;
+    use zip::write::SimpleFileOptions;
+
+    /// `(name, type, contents or link target)`, names are written as is.
+    fn tar_gz(dir: &Path, entries: &[(&str, EntryType, &str)]) -> PathBuf {
+        let path = dir.join("asset.tar.gz");
+        let gz = flate2::write::GzEncoder::new(fs::File::create(&path).unwrap(), flate2::Compression::fast());
+        let mut builder = tar::Builder::new(gz);
+
+        for (name, kind, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            // set_path() refuses `..`, which is what these tests are about
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_mode(0o644);
+            let body: &[u8] = if kind.is_file() {
+                header.set_size(data.len() as u64);
+                data.as_bytes()
+            } else {
+                header.set_link_name(data).unwrap();
+                header.set_size(0);
+                &[]
+            };
+            header.set_cksum();
+            builder.append(&header, body).unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    /// `(name, contents)`, or a symlink to `target` with `symlink = true`.
+    fn zip(dir: &Path, entries: &[(&str, &str, bool)]) -> PathBuf {
+        let path = dir.join("asset.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+
+        for (name, data, symlink) in entries {
+            if *symlink {
+                zip.add_symlink(*name, *data, options).unwrap();
+            } else {
+                zip.start_file(*name, options).unwrap();
+                zip.write_all(data.as_bytes()).unwrap();
+            }
+        }
+
+        zip.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn safe_name_rejects_paths_leaving_the_archive() {
+        assert_eq!(safe_name(Path::new("./pkg/libfoo.so")).as_deref(), Some("pkg/libfoo.so"));
+        assert_eq!(safe_name(Path::new("../libfoo.so")), None);
+        assert_eq!(safe_name(Path::new("pkg/../../libfoo.so")), None);
+        assert_eq!(safe_name(Path::new("/usr/lib/libfoo.so")), None);
+        assert_eq!(safe_name(Path::new(".")), None);
+    }
+
+    #[test]
+    fn format_from_url() {
+        assert!(matches!(ArchiveFormat::from_url("https://x/foo.tar.gz?raw=1"), Some(ArchiveFormat::TarGz)));
+        assert!(matches!(ArchiveFormat::from_url("https://x/foo.tgz"), Some(ArchiveFormat::TarGz)));
+        assert!(matches!(ArchiveFormat::from_url("https://x/foo.zip#top"), Some(ArchiveFormat::Zip)));
+        assert!(ArchiveFormat::from_url("https://x/libfoo.so").is_none());
+    }
+
+    #[test]
+    fn tar_extracts_the_only_match() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let archive = tar_gz(dir, &[
+            ("foo-1.0/README.md", EntryType::Regular, "readme"),
+            ("foo-1.0/libfoo.so", EntryType::Regular, "elf"),
+        ]);
+        let dst = dir.join("out.so");
+
+        assert_eq!(extract(ArchiveFormat::TarGz, &archive, None, &dst).unwrap(), "foo-1.0/libfoo.so");
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "elf");
+    }
+
+    #[test]
+    fn tar_skips_parent_and_absolute_paths() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let archive = tar_gz(dir, &[
+            ("../libevil.so", EntryType::Regular, "evil"),
+            ("/tmp/libabsolute.so", EntryType::Regular, "evil"),
+            ("lib/libfoo.so", EntryType::Regular, "elf"),
+        ]);
+        let dst = dir.join("out.so");
+
+        assert_eq!(extract(ArchiveFormat::TarGz, &archive, None, &dst).unwrap(), "lib/libfoo.so");
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "elf");
+    }
+
+    #[test]
+    fn tar_skips_symlinks_and_hardlinks() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let archive = tar_gz(dir, &[
+            ("libfoo.so", EntryType::Symlink, "/etc/passwd"),
+            ("libbar.so", EntryType::Link, "data"),
+            ("data", EntryType::Regular, "secret"),
+        ]);
+
+        let err = extract(ArchiveFormat::TarGz, &archive, None, &dir.join("out.so")).unwrap_err();
+        assert_eq!(err.to_string(), "archive has no file matching '*.so'");
+        assert!(!dir.join("out.so").exists());
+    }
+
+    #[test]
+    fn tar_refuses_several_matches() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let archive = tar_gz(dir, &[
+            ("x86_64/libfoo.so", EntryType::Regular, "a"),
+            ("aarch64/libfoo.so", EntryType::Regular, "b"),
+        ]);
+        let dst = dir.join("out.so");
+
+        let err = extract(ArchiveFormat::TarGz, &archive, Some("libfoo.so"), &dst).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "archive has more than one file matching 'libfoo.so': x86_64/libfoo.so, aarch64/libfoo.so"
+        );
+
+        // a pattern with `/` is matched against the whole path
+        assert_eq!(extract(ArchiveFormat::TarGz, &archive, Some("aarch64/*.so"), &dst).unwrap(), "aarch64/libfoo.so");
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "b");
+    }
+
+    #[test]
+    fn zip_extracts_the_only_match() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let archive = zip(dir, &[("README.md", "readme", false), ("dist/libfoo.so", "elf", false)]);
+        let dst = dir.join("out.so");
+
+        assert_eq!(extract(ArchiveFormat::Zip, &archive, None, &dst).unwrap(), "dist/libfoo.so");
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "elf");
+    }
+
+    #[test]
+    fn zip_skips_parent_and_absolute_paths() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let archive = zip(dir, &[
+            ("../libevil.so", "evil", false),
+            ("/tmp/libabsolute.so", "evil", false),
+            ("libfoo.so", "elf", false),
+        ]);
+        let dst = dir.join("out.so");
+
+        assert_eq!(extract(ArchiveFormat::Zip, &archive, None, &dst).unwrap(), "libfoo.so");
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "elf");
+    }
+
+    #[test]
+    fn zip_skips_symlinks() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let archive = zip(dir, &[("libfoo.so", "/etc/passwd", true), ("README.md", "readme", false)]);
+
+        let err = extract(ArchiveFormat::Zip, &archive, None, &dir.join("out.so")).unwrap_err();
+        assert_eq!(err.to_string(), "archive has no file matching '*.so'");
+        assert!(!dir.join("out.so").exists());
+    }
+
+    #[test]
+    fn zip_refuses_several_matches() {
+        let tmp = TempDir::new().unwrap();
+        let dir = tmp.path();
+        let archive = zip(dir, &[("libfoo.so", "a", false), ("libbar.so", "b", false)]);
+
+        let err = extract(ArchiveFormat::Zip, &archive, None, &dir.join("out.so")).unwrap_err();
+        assert_eq!(err.to_string(), "archive has more than one file matching '*.so': libfoo.so, libbar.so");
+    }
+}
@@ -0,0 +1,206 @@
+//! Companion files a plugin installs next to its `.so` (`artifacts = [...]`).
+//!
+//! Each entry is a path or glob inside the plugin's source, optionally mapped
+//! to a destination inside the config directory with `src -> dest`. Without a
+//! destination, files go to `plugins/<name>/`. Files matched by a glob keep
+//! their path below the glob's first wildcard component, so
+//! `assets/**/*.svg -> icons/` installs `assets/dark/a.svg` as `icons/dark/a.svg`.
+
+use crate::error::Failure;
+use anyhow::{bail, Context, Result};
+use glob::{MatchOptions, Pattern};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// A file to install: `src` inside the plugin's source, `dst` relative to the config directory.
+pub struct Mapping {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+}
+
+/// Expand `specs` against `src_dir`. `default_dir` is where entries without
+/// a destination go, relative to the config directory.
+pub fn resolve(specs: &[String], src_dir: &Path, default_dir: &Path) -> Result<Vec<Mapping>> {
+    let mut mappings: Vec<Mapping> = vec![];
+
+    for spec in specs {
+        let (src, dest) = match spec.split_once("->") {
+            Some((src, dest)) => (src.trim(), Some(dest.trim())),
+            None => (spec.trim(), None),
+        };
+
+        let src_rel = relative(src).ok_or_else(|| {
+            Failure::Config(format!("artifact '{}' must be a relative path inside the plugin", src))
+        })?;
+        let dest_rel = match dest {
+            Some(dest) => relative(dest).ok_or_else(|| {
+                Failure::Config(format!("artifact destination '{}' must be a relative path inside the config directory", dest))
+            })?,
+            None => default_dir.to_path_buf(),
+        };
+
+        let is_glob = src.contains(['*', '?', '[']);
+        // a single file mapped to a path not ending in `/` is renamed, anything else goes into a directory
+        let into_dir = is_glob || dest.is_none_or(|d| d.ends_with('/'));
+
+        let base: PathBuf = src_rel
+            .components()
+            .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+            .collect();
+        let base = if is_glob { base } else { base.parent().map(Path::to_path_buf).unwrap_or_default() };
+
+        let matched = expand(src_dir, &src_rel)?;
+        if matched.is_empty() {
+            bail!(Failure::Build(format!("artifact '{}' matched no files in {}", src, src_dir.display())));
+        }
+
+        for file in matched {
+            let dst = if into_dir {
+                let below_base = file.strip_prefix(src_dir.join(&base)).unwrap_or(&file);
+                dest_rel.join(below_base)
+            } else {
+                dest_rel.clone()
+            };
+            if let Some(other) = mappings.iter().find(|m| m.dst == dst && m.src != file) {
+                bail!(Failure::Config(format!(
+                    "{} and {} would both be installed as {}",
+                    other.src.strip_prefix(src_dir).unwrap_or(&other.src).display(),
+                    file.strip_prefix(src_dir).unwrap_or(&file).display(),
+                    dst.display(),
+                )));
+            }
+            if !mappings.iter().any(|m| m.dst == dst) {
+                mappings.push(Mapping { src: file, dst });
+            }
+        }
+    }
+
+    Ok(mappings)
+}
+
+/// Files in `src_dir` matching `pattern`.
+fn expand(src_dir: &Path, pattern: &Path) -> Result<Vec<PathBuf>> {
+    let full = format!("{}/{}", Pattern::escape(&src_dir.to_string_lossy()), pattern.to_string_lossy());
+    let options = MatchOptions { require_literal_separator: true, ..MatchOptions::new() };
+
+    let paths = glob::glob_with(&full, options)
+        .map_err(|e| Failure::Config(format!("invalid artifact pattern '{}': {}", pattern.display(), e)))?;
+
+    let mut files = vec![];
+    for path in paths {
+        let path = path.context("failed to read plugin source")?;
+        // symlinks could point anywhere on the machine
+        if fs::symlink_metadata(&path).is_ok_and(|m| m.is_file()) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// `path` if it is relative and can't climb out of the directory it's joined to.
+fn relative(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let mut clean = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => clean.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!clean.as_os_str().is_empty()).then_some(clean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A plugin source with the given files, each containing its own path.
+    fn source(files: &[&str]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, file).unwrap();
+        }
+        dir
+    }
+
+    fn run(dir: &TempDir, specs: &[&str]) -> Result<Vec<(String, String)>> {
+        let specs: Vec<String> = specs.iter().map(|s| s.to_string()).collect();
+        let mappings = resolve(&specs, dir.path(), Path::new("plugins/x"))?;
+        Ok(mappings
+            .into_iter()
+            .map(|m| {
+                let src = m.src.strip_prefix(dir.path()).unwrap().to_string_lossy().into_owned();
+                (src, m.dst.to_string_lossy().into_owned())
+            })
+            .collect())
+    }
+
+    fn pairs(expected: &[(&str, &str)]) -> Vec<(String, String)> {
+        expected.iter().map(|(s, d)| (s.to_string(), d.to_string())).collect()
+    }
+
+    #[test]
+    fn default_destination() {
+        let dir = source(&["config.rhai", "data/theme.toml"]);
+        let got = run(&dir, &["config.rhai", "./data/theme.toml"]).unwrap();
+        assert_eq!(got, pairs(&[("config.rhai", "plugins/x/config.rhai"), ("data/theme.toml", "plugins/x/theme.toml")]));
+    }
+
+    #[test]
+    fn file_renamed_or_put_in_directory() {
+        let dir = source(&["data/theme.toml"]);
+        let got = run(&dir, &["data/theme.toml -> themes/dark.toml", "data/theme.toml -> themes/"]).unwrap();
+        assert_eq!(
+            got,
+            pairs(&[("data/theme.toml", "themes/dark.toml"), ("data/theme.toml", "themes/theme.toml")])
+        );
+    }
+
+    #[test]
+    fn glob_keeps_path_below_wildcard() {
+        let dir = source(&["assets/a.svg", "assets/dark/b.svg", "assets/c.png"]);
+
+        let mut got = run(&dir, &["assets/**/*.svg -> icons/"]).unwrap();
+        got.sort();
+        assert_eq!(got, pairs(&[("assets/a.svg", "icons/a.svg"), ("assets/dark/b.svg", "icons/dark/b.svg")]));
+
+        // several matches can't share one file, a destination without `/` is still a directory
+        let got = run(&dir, &["assets/*.svg -> icons"]).unwrap();
+        assert_eq!(got, pairs(&[("assets/a.svg", "icons/a.svg")]));
+    }
+
+    #[test]
+    fn no_matches() {
+        let dir = source(&["config.rhai"]);
+        let err = run(&dir, &["*.svg"]).unwrap_err().to_string();
+        assert!(err.contains("matched no files"), "{}", err);
+        assert!(run(&dir, &["missing.rhai"]).is_err());
+    }
+
+    #[test]
+    fn two_files_to_one_destination() {
+        let dir = source(&["a/config.rhai", "b/config.rhai"]);
+        let err = run(&dir, &["a/config.rhai", "b/config.rhai"]).unwrap_err().to_string();
+        assert!(err.contains("would both be installed as plugins/x/config.rhai"), "{}", err);
+
+        let err = run(&dir, &["a/config.rhai -> one.rhai", "b/config.rhai -> one.rhai"]).unwrap_err().to_string();
+        assert!(err.contains("would both be installed as one.rhai"), "{}", err);
+
+        // the same file listed twice is fine
+        let got = run(&dir, &["a/config.rhai", "a/*.rhai"]).unwrap();
+        assert_eq!(got, pairs(&[("a/config.rhai", "plugins/x/config.rhai")]));
+    }
+
+    #[test]
+    fn paths_outside_are_refused() {
+        let dir = source(&["config.rhai"]);
+        for spec in ["../config.rhai", "/etc/passwd", "data/../../config.rhai", "config.rhai -> ../x", "config.rhai -> /tmp/x", "."] {
+            let err = run(&dir, &[spec]).unwrap_err().to_string();
+            assert!(err.contains("must be a relative path"), "{}: {}", spec, err);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Resolving and running plugin build commands.
+//!
+//! Settings in plugins.toml win over the ones in the plugin's own plugin.toml.
+//! String commands are split with shell quoting rules and run directly, unless
+//! they use shell syntax (`&&`, pipes, redirects, `$VARS`, globs) or set
+//! `shell = true`, in which case they go through `sh -c`. List commands are
+//! always run as is.
+//!
+//! Build output is written to a log per plugin and commit under the cache
+//! (`logs/<plugin>/<sha>.log`) and only streamed to the terminal with `--debug`.
+
+use crate::cancel;
+use crate::error::Failure;
+use crate::git;
+use crate::schema::{BuildCommand, PluginEntry, PluginManifestInner};
+use crate::utils;
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use indexmap::IndexMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_BUILD: &str = "cargo build --release";
+
+/// Lines of the build log shown when a build fails
+const TAIL_LINES: usize = 20;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time a build gets to exit after SIGTERM before it is killed
+const KILL_GRACE: Duration = Duration::from_secs(3);
+
+/// Time the rest of the build output gets to arrive once the build exited
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct BuildSpec {
+    pub command: BuildCommand,
+    pub env: IndexMap<String, String>,
+    /// Relative to the plugin source
+    pub workdir: Option<String>,
+    pub shell: bool,
+    pub timeout: Option<Duration>,
+}
+
+impl BuildSpec {
+    pub fn resolve(entry: &PluginEntry, manifest: Option<&PluginManifestInner>, default_timeout: Option<u64>) -> Self {
+        let config = entry.config();
+
+        let command = config
+            .and_then(|c| c.build.clone())
+            .or_else(|| manifest.and_then(|m| m.build.clone()))
+            .unwrap_or_else(|| BuildCommand::Line(DEFAULT_BUILD.to_string()));
+
+        let mut env = manifest.and_then(|m| m.env.clone()).unwrap_or_default();
+        env.extend(config.and_then(|c| c.env.clone()).unwrap_or_default());
+
+        let workdir = config
+            .and_then(|c| c.workdir.clone())
+            .or_else(|| manifest.and_then(|m| m.workdir.clone()));
+
+        let shell = config
+            .and_then(|c| c.shell)
+            .or_else(|| manifest.and_then(|m| m.shell))
+            .unwrap_or(false);
+
+        let timeout = config
+            .and_then(|c| c.timeout)
+            .or(default_timeout)
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs);
+
+        BuildSpec { command, env, workdir, shell, timeout }
+    }
+
+    /// Run the build inside `src_dir` (or its `workdir`), capturing its output to `log_path`.
+    pub fn run(&self, repo: &str, src_dir: &Path, log_path: &Path) -> Result<()> {
+        let cwd = match &self.workdir {
+            Some(dir) => src_dir.join(dir),
+            None => src_dir.to_path_buf(),
+        };
+        if !cwd.is_dir() {
+            bail!(Failure::Config(format!("build workdir {} does not exist", cwd.display())));
+        }
+
+        if let Some(dir) = log_path.parent() {
+            fs::create_dir_all(dir).context("failed to create build log dir")?;
+        }
+        let log = File::create(log_path)
+            .with_context(|| format!("failed to create {}", log_path.display()))?;
+
+        // one pipe for stdout and stderr keeps their lines in order
+        let (output, writer) = io::pipe().context("failed to create build output pipe")?;
+
+        let mut command = self.command()?;
+        command
+            .current_dir(&cwd)
+            .stdin(Stdio::null())
+            .stdout(writer.try_clone().context("failed to create build output pipe")?)
+            .stderr(writer)
+            // own process group, so a timeout or Ctrl-C can stop everything the build spawned
+            .process_group(0);
+
+        log::debug!("running '{}' in {}", self.command, cwd.display());
+        let spawned = command.spawn();
+        // close our copies of the write end so `capture` sees EOF when the build exits
+        drop(command);
+        let mut child = spawned
+            .map_err(|e| Failure::Build(format!("failed to spawn '{}': {}", self.command, e)))?;
+
+        let (captured, done) = mpsc::channel();
+        thread::spawn(move || {
+            capture(output, log);
+            let _ = captured.send(());
+        });
+
+        let exit = wait(&mut child, self.timeout)
+            .map_err(|e| Failure::Build(format!("failed to wait for '{}': {}", self.command, e)))?;
+
+        // whatever the build left running in the background would hold the
+        // output pipe open, and this build with it
+        signal_group(child.id() as libc::pid_t, libc::SIGKILL);
+        if done.recv_timeout(DRAIN_TIMEOUT).is_err() {
+            log::debug!("output of '{}' is still open after it exited, not waiting for it", self.command);
+        }
+
+        let status = match exit {
+            Exit::Status(status) => status,
+            Exit::Cancelled => bail!(Failure::Interrupted),
+            Exit::TimedOut => {
+                print_tail(repo, log_path);
+                bail!(Failure::Build(format!(
+                    "command '{}' timed out after {}s, full log at {}",
+                    self.command,
+                    self.timeout.unwrap_or_default().as_secs(),
+                    log_path.display()
+                )));
+            }
+        };
+
+        if !status.success() {
+            print_tail(repo, log_path);
+            bail!(Failure::Build(format!(
+                "command '{}' exited with {}, full log at {}",
+                self.command,
+                status,
+                log_path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    fn command(&self) -> Result<Command> {
+        let (assignments, argv) = match &self.command {
+            BuildCommand::Args(args) => (vec![], args.clone()),
+            BuildCommand::Line(line) if self.shell || needs_shell(line) => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(line).envs(&self.env);
+                return Ok(command);
+            }
+            BuildCommand::Line(line) => {
+                let words = shell_words::split(line).map_err(|e| {
+                    Failure::Config(format!("invalid build command '{}': {}", line, e))
+                })?;
+                split_assignments(words)
+            }
+        };
+
+        let Some((bin, args)) = argv.split_first() else {
+            bail!(Failure::Config("build command is empty".into()));
+        };
+
+        let mut command = Command::new(bin);
+        // `FOO=bar cargo build` overrides `env` like it would in a shell
+        command.args(args).envs(&self.env).envs(assignments);
+        Ok(command)
+    }
+}
+
+enum Exit {
+    Status(ExitStatus),
+    TimedOut,
+    Cancelled,
+}
+
+fn wait(child: &mut Child, timeout: Option<Duration>) -> io::Result<Exit> {
+    let started = Instant::now();
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Exit::Status(status));
+        }
+
+        let exit = if cancel::requested() {
+            Exit::Cancelled
+        } else if timeout.is_some_and(|t| started.elapsed() >= t) {
+            Exit::TimedOut
+        } else {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        };
+
+        terminate(child)?;
+        return Ok(exit);
+    }
+}
+
+/// SIGTERM the build's process group, then SIGKILL whatever is left after a grace period.
+fn terminate(child: &mut Child) -> io::Result<()> {
+    let pgid = child.id() as libc::pid_t;
+    signal_group(pgid, libc::SIGTERM);
+
+    let started = Instant::now();
+    while started.elapsed() < KILL_GRACE && child.try_wait()?.is_none() {
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    signal_group(pgid, libc::SIGKILL);
+    child.wait()?;
+    Ok(())
+}
+
+fn signal_group(pgid: libc::pid_t, signal: libc::c_int) {
+    // SAFETY: kill() has no memory safety requirements, a stale group only yields ESRCH
+    unsafe {
+        libc::kill(-pgid, signal);
+    }
+}
+
+/// Directory holding the build logs of the plugin with the plugins.toml key `repo`.
+pub fn log_dir(cache_root: &Path, repo: &str) -> PathBuf {
+    cache_root.join("logs").join(utils::dir_name(repo))
+}
+
+/// Log file for building `src_dir` at its current commit.
+pub fn log_path(cache_root: &Path, repo: &str, src_dir: &Path) -> PathBuf {
+    let name = match git::worktree_state(src_dir) {
+        Ok((sha, true)) => format!("{}-dirty", sha),
+        Ok((sha, false)) => sha,
+        Err(_) => "local".to_string(),
+    };
+    log_dir(cache_root, repo).join(format!("{}.log", name))
+}
+
+/// Most recently written build log of a plugin.
+pub fn latest_log(cache_root: &Path, repo: &str) -> Option<PathBuf> {
+    fs::read_dir(log_dir(cache_root, repo))
+        .ok()?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().extension().is_some_and(|x| x == "log"))
+        .max_by_key(|e| e.metadata().and_then(|m| m.modified()).ok())
+        .map(|e| e.path())
+}
+
+/// Copy the build output into its log, echoing it with `--debug`.
+fn capture(output: impl Read, mut log: File) {
+    for line in BufReader::new(output).split(b'\n').map_while(Result::ok) {
+        let line = String::from_utf8_lossy(&line);
+        log::debug!("{}", line);
+        let _ = writeln!(log, "{}", line);
+    }
+}
+
+fn print_tail(repo: &str, log_path: &Path) {
+    // already streamed in full
+    if log::max_level() >= log::LevelFilter::Debug {
+        return;
+    }
+    let Ok(contents) = fs::read_to_string(log_path) else {
+        return;
+    };
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let tail = &lines[lines.len().saturating_sub(TAIL_LINES)..];
+    if tail.is_empty() {
+        return;
+    }
+
+    let mut out = format!("{} {}:", "build output of".red(), repo.bold());
+    for line in tail {
+        out.push_str(&format!("\n  {}", line.dimmed()));
+    }
+    log::info!("{}", out);
+}
+
+/// Split leading `NAME=value` words off a command line.
+fn split_assignments(words: Vec<String>) -> (Vec<(String, String)>, Vec<String>) {
+    let count = words.iter().take_while(|w| assignment(w).is_some()).count();
+    let mut words = words;
+    let argv = words.split_off(count);
+
+    let assignments = words
+        .iter()
+        .filter_map(|w| assignment(w))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+    (assignments, argv)
+}
+
+fn assignment(word: &str) -> Option<(&str, &str)> {
+    let (name, value) = word.split_once('=')?;
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then_some((name, value))
+}
+
+/// Whether a command line uses syntax only a shell understands.
+fn needs_shell(line: &str) -> bool {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for c in line.chars() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (_, '$' | '`') => return true,
+            (Some(_), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '&' | '|' | ';' | '<' | '>' | '(' | ')' | '*' | '?' | '~' | '\n') => return true,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn spec(line: &str) -> BuildSpec {
+        BuildSpec {
+            command: BuildCommand::Line(line.to_string()),
+            env: IndexMap::new(),
+            workdir: None,
+            shell: false,
+            timeout: None,
+        }
+    }
+
+    fn words(line: &str) -> Vec<String> {
+        shell_words::split(line).unwrap()
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn quoted_arguments_run_directly() {
+        assert!(!needs_shell("make CFLAGS='-O2 -g'"));
+        assert!(!needs_shell(r#"cargo build --features "a b""#));
+
+        let (assignments, argv) = split_assignments(words("make CFLAGS='-O2 -g'"));
+        assert!(assignments.is_empty());
+        assert_eq!(argv, strings(&["make", "CFLAGS=-O2 -g"]));
+    }
+
+    #[test]
+    fn shell_syntax_needs_a_shell() {
+        assert!(needs_shell("cd sub && cargo build"));
+        assert!(needs_shell("cargo build 2>&1 | tee log"));
+        assert!(needs_shell("cp target/*.so ."));
+        assert!(needs_shell("make -C ~/src"));
+
+        let command = spec("cd sub && cargo build").command().unwrap();
+        assert_eq!(command.get_program(), "sh");
+        assert_eq!(command.get_args().collect::<Vec<_>>(), ["-c", "cd sub && cargo build"]);
+    }
+
+    #[test]
+    fn operators_inside_quotes_are_arguments() {
+        assert!(!needs_shell("echo 'a && b'"));
+        assert!(!needs_shell(r#"echo "a | b; c""#));
+        assert!(!needs_shell(r"echo a\&\&b"));
+    }
+
+    #[test]
+    fn dollar_signs() {
+        // expanded by a shell, except when escaped or single quoted
+        assert!(needs_shell("cargo build --target $TARGET"));
+        assert!(needs_shell(r#"cargo build --target "$TARGET""#));
+        assert!(needs_shell("cargo build --target `uname -m`"));
+        assert!(!needs_shell(r"echo \$TARGET"));
+        assert!(!needs_shell("echo '$TARGET'"));
+    }
+
+    #[test]
+    fn leading_assignments_become_env() {
+        assert!(!needs_shell("FOO=bar cargo build"));
+
+        let (assignments, argv) = split_assignments(words("FOO=bar RUSTFLAGS='-C opt-level=3' cargo build"));
+        assert_eq!(
+            assignments,
+            [("FOO".to_string(), "bar".to_string()), ("RUSTFLAGS".to_string(), "-C opt-level=3".to_string())]
+        );
+        assert_eq!(argv, strings(&["cargo", "build"]));
+
+        let command = spec("FOO=bar cargo build").command().unwrap();
+        assert_eq!(command.get_program(), "cargo");
+        assert!(command.get_envs().any(|(k, v)| k == "FOO" && v == Some(OsStr::new("bar"))));
+    }
+
+    #[test]
+    fn only_leading_valid_names_are_assignments() {
+        let (assignments, argv) = split_assignments(words("1FOO=bar make X=1"));
+        assert!(assignments.is_empty());
+        assert_eq!(argv, strings(&["1FOO=bar", "make", "X=1"]));
+    }
+
+    #[test]
+    fn assignment_only_line_is_empty() {
+        let (assignments, argv) = split_assignments(words("FOO=bar"));
+        assert_eq!(assignments, [("FOO".to_string(), "bar".to_string())]);
+        assert!(argv.is_empty());
+
+        let err = spec("FOO=bar").command().unwrap_err();
+        assert_eq!(err.to_string(), "build command is empty");
+    }
+}
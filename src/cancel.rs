@@ -0,0 +1,38 @@
+//! Ctrl-C handling.
+//!
+//! The first Ctrl-C asks running builds and fetches to stop and lets the
+//! command clean up and record what already finished. A second one exits
+//! immediately.
+
+use crate::error::{Failure, EXIT_INTERRUPTED};
+use anyhow::{bail, Result};
+use colored::Colorize;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+pub fn install_handler() {
+    let result = ctrlc::set_handler(|| {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            process::exit(EXIT_INTERRUPTED);
+        }
+        log::warn!("{}", "interrupted, stopping (press Ctrl-C again to force)".yellow());
+    });
+
+    if let Err(e) = result {
+        log::debug!("failed to install Ctrl-C handler: {}", e);
+    }
+}
+
+pub fn requested() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Bail out if Ctrl-C was pressed.
+pub fn check() -> Result<()> {
+    if requested() {
+        bail!(Failure::Interrupted);
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+//! The part of a plugin's changelog that an update brings in.
+//!
+//! The changelog is split into sections at its `## ` headings. A section is
+//! shown when it is new or its text changed since the installed revision,
+//! which covers freshly released versions as well as a growing `Unreleased`
+//! section on a branch.
+
+use crate::git;
+use std::fs;
+use std::path::Path;
+
+/// File names a changelog is looked for under, at the root of the plugin.
+pub const FILE_NAMES: &[&str] = &["CHANGELOG.md", "Changelog.md", "changelog.md", "CHANGES.md"];
+
+/// Sections of `name` in the checkout at `repo_path` that changed since
+/// commit `from`, or None if `from` isn't in the repository.
+pub fn changed_since(repo_path: &Path, from: &str, name: &str) -> Option<Vec<String>> {
+    let new = fs::read_to_string(repo_path.join(name)).unwrap_or_default();
+    let old = match git::read_file_at(repo_path, from, name) {
+        // a changelog added since then is new as a whole
+        Ok(old) => old.unwrap_or_default(),
+        Err(e) => {
+            log::debug!("no changes to {} since {}: {}", name, from, e);
+            return None;
+        }
+    };
+    Some(changed_sections(&old, &new))
+}
+
+/// Sections of `new` that `old` doesn't have as they are, in the order of `new`.
+pub fn changed_sections(old: &str, new: &str) -> Vec<String> {
+    let old = sections(old);
+    sections(new).into_iter().filter(|s| !old.contains(s)).collect()
+}
+
+/// Every `## ` section of `text`, from its heading up to the next one.
+fn sections(text: &str) -> Vec<String> {
+    let mut sections: Vec<Vec<&str>> = vec![];
+    let mut in_code = false;
+
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+        if !in_code && line.starts_with("## ") {
+            sections.push(vec![]);
+        }
+        // link definitions at the bottom change with every release
+        if is_link_definition(line) {
+            continue;
+        }
+        if let Some(section) = sections.last_mut() {
+            section.push(line);
+        }
+    }
+
+    sections.into_iter().map(|lines| lines.join("\n").trim_end().to_string()).collect()
+}
+
+/// `[1.2.0]: https://...`
+fn is_link_definition(line: &str) -> bool {
+    line.starts_with('[') && line.split_once("]:").is_some_and(|(label, _)| !label.contains(']'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Repository, Signature};
+    use tempfile::TempDir;
+
+    /// Commit `files` on top of HEAD, returning the new commit's id.
+    fn commit(repo: &Repository, files: &[(&str, &str)]) -> String {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (name, contents) in files {
+            fs::write(workdir.join(name), contents).unwrap();
+            index.add_path(Path::new(name)).unwrap();
+        }
+        index.write().unwrap();
+
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("eiipm", "eiipm@localhost").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, "commit", &tree, &parents).unwrap().to_string()
+    }
+
+    const V1: &str = "# Changelog
+
+## [1.0.0] - 2026-01-10
+- first release
+
+[1.0.0]: https://example.com/compare/v0.9.0...v1.0.0
+";
+
+    const V2: &str = "# Changelog
+
+## [1.2.0] - 2026-03-02
+- even more
+
+## [1.1.0] - 2026-02-01
+- more
+
+## [1.0.0] - 2026-01-10
+- first release
+
+[1.2.0]: https://example.com/compare/v1.1.0...v1.2.0
+[1.1.0]: https://example.com/compare/v1.0.0...v1.1.0
+[1.0.0]: https://example.com/compare/v0.9.0...v1.0.0
+";
+
+    #[test]
+    fn sections_between_versions() {
+        assert_eq!(
+            changed_sections(V1, V2),
+            vec!["## [1.2.0] - 2026-03-02\n- even more", "## [1.1.0] - 2026-02-01\n- more"]
+        );
+        assert!(changed_sections(V2, V2).is_empty());
+    }
+
+    #[test]
+    fn missing_changelog() {
+        // nothing was installed before, every section is new
+        assert_eq!(changed_sections("", V1), vec!["## [1.0.0] - 2026-01-10\n- first release"]);
+        assert!(changed_sections(V1, "").is_empty());
+    }
+
+    #[test]
+    fn changes_since_a_commit() {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let without = commit(&repo, &[("README.md", "plugin")]);
+        let v1 = commit(&repo, &[("CHANGELOG.md", V1)]);
+        commit(&repo, &[("CHANGELOG.md", V2)]);
+
+        assert_eq!(changed_since(dir.path(), &v1, "CHANGELOG.md").unwrap(), changed_sections(V1, V2));
+        // the changelog didn't exist yet, all of it is new
+        assert_eq!(changed_since(dir.path(), &without, "CHANGELOG.md").unwrap().len(), 3);
+        // the installed commit isn't in the cache, e.g. after a force-push
+        assert_eq!(changed_since(dir.path(), "0123456789abcdef0123456789abcdef01234567", "CHANGELOG.md"), None);
+    }
+
+    #[test]
+    fn headings_without_versions() {
+        let old = "# Changelog\n\n## Unreleased\n- fix a\n\n## Notes\nbuilt with rustc 1.85\n";
+        let new = "# Changelog for my plugin\n\n## Unreleased\n- fix a\n- fix b\n\n## Notes\nbuilt with rustc 1.85\n";
+        assert_eq!(changed_sections(old, new), vec!["## Unreleased\n- fix a\n- fix b"]);
+    }
+
+    #[test]
+    fn headings_in_code_blocks() {
+        let new = "## 1.1.0\n```md\n## not a heading\n```\n\n## 1.0.0\n- first\n";
+        assert_eq!(changed_sections("## 1.0.0\n- first\n", new), vec!["## 1.1.0\n```md\n## not a heading\n```"]);
+    }
+
+    #[test]
+    fn link_definitions() {
+        assert!(is_link_definition("[1.2.0]: https://example.com"));
+        assert!(!is_link_definition("[link](https://example.com): text"));
+        assert!(!is_link_definition("- [1.2.0]: text"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Format-preserving edits of plugins.toml.
+//!
+//! Reading goes through `schema::PluginsFile`, but every command that changes
+//! plugins.toml edits the document in place so comments, ordering, whitespace
+//! and inline-table style written by `init` or the user survive.
+
+use crate::error::Failure;
+use crate::schema::PluginEntry;
+use crate::transaction;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml_edit::{DocumentMut, InlineTable, Item, Table, TableLike, Value};
+
+pub struct PluginsDocument {
+    path: PathBuf,
+    doc: DocumentMut,
+}
+
+impl PluginsDocument {
+    pub fn open(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let doc = contents
+            .parse::<DocumentMut>()
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+
+        Ok(PluginsDocument { path: path.to_path_buf(), doc })
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.doc
+            .get("plugins")
+            .and_then(Item::as_table_like)
+            .is_some_and(|t| t.contains_key(key))
+    }
+
+    /// Append a plugin entry, keeping every existing line untouched.
+    pub fn insert(&mut self, key: &str, entry: &PluginEntry) -> Result<()> {
+        let value = toml::Value::try_from(entry).context("failed to serialize plugin entry")?;
+        self.plugins_mut()?.insert(key, Item::Value(to_edit_value(value)));
+        Ok(())
+    }
+
+    /// Remove a plugin entry along with the comments directly above it.
+    pub fn remove(&mut self, key: &str) -> Result<bool> {
+        Ok(self.plugins_mut()?.remove(key).is_some())
+    }
+
+    /// Write the document back, atomically so a crash can't truncate the user's file.
+    pub fn save(&self) -> Result<()> {
+        transaction::write_atomic(&self.path, self.doc.to_string())
+    }
+
+    fn plugins_mut(&mut self) -> Result<&mut dyn TableLike> {
+        if !self.doc.contains_key("plugins") {
+            // comments at the end of the file would otherwise end up below the new table
+            let mut table = Table::new();
+            let trailing = self.doc.trailing().as_str().unwrap_or_default();
+            if !trailing.trim().is_empty() {
+                table.decor_mut().set_prefix(format!("{}\n", trailing));
+            }
+            self.doc.set_trailing("");
+            self.doc.insert("plugins", Item::Table(table));
+        }
+
+        let item = self.doc.entry("plugins").or_insert_with(|| Item::Table(Table::new()));
+        match item.as_table_like_mut() {
+            Some(t) => Ok(t),
+            None => bail!(Failure::Config("'plugins' in plugins.toml is not a table".into())),
+        }
+    }
+}
+
+/// Convert a serialized value into an inline `toml_edit` value.
+fn to_edit_value(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::from(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::from(b),
+        toml::Value::Datetime(d) => Value::from(d),
+        toml::Value::Array(items) => {
+            Value::Array(items.into_iter().map(to_edit_value).collect())
+        }
+        toml::Value::Table(table) => {
+            let mut inline = InlineTable::new();
+            for (k, v) in table {
+                inline.insert(&k, to_edit_value(v));
+            }
+            Value::InlineTable(inline)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::PluginConfig;
+    use tempfile::TempDir;
+
+    fn parse(contents: &str) -> PluginsDocument {
+        PluginsDocument { path: PathBuf::new(), doc: contents.parse().unwrap() }
+    }
+
+    const DOC: &str = r#"# my plugins
+[plugins]
+# window tiling
+"user/tiler" = "main"   # pinned by hand
+"user/bar" = { ref = "v2", name = "bar" }
+
+# keep last
+"user/clock" = "dev"
+"#;
+
+    #[test]
+    fn insert_appends_and_keeps_the_rest() {
+        let mut doc = parse(DOC);
+        doc.insert("user/new", &PluginEntry::Ref("main".into())).unwrap();
+
+        let out = doc.doc.to_string();
+        assert!(out.starts_with(DOC), "{}", out);
+        assert!(out.ends_with("\"user/new\" = \"main\"\n"), "{}", out);
+    }
+
+    #[test]
+    fn insert_writes_config_inline() {
+        let mut doc = parse("[plugins]\n");
+        let config = PluginConfig { ref_: Some("v1".into()), name: Some("x".into()), ..Default::default() };
+        doc.insert("user/x", &PluginEntry::Config(Box::new(config))).unwrap();
+
+        let out = doc.doc.to_string();
+        assert!(out.contains("\"user/x\" = { ref = \"v1\""), "{}", out);
+        assert!(out.contains("name = \"x\""), "{}", out);
+        assert!(doc.contains("user/x"));
+    }
+
+    #[test]
+    fn insert_creates_plugins_table() {
+        let mut doc = parse("# nothing yet\n");
+        doc.insert("user/x", &PluginEntry::Ref("main".into())).unwrap();
+
+        assert_eq!(doc.doc.to_string(), "# nothing yet\n\n[plugins]\n\"user/x\" = \"main\"\n");
+
+        let mut doc = parse("");
+        doc.insert("user/x", &PluginEntry::Ref("main".into())).unwrap();
+        assert_eq!(doc.doc.to_string(), "[plugins]\n\"user/x\" = \"main\"\n");
+    }
+
+    #[test]
+    fn remove_takes_its_comments_along() {
+        let mut doc = parse(DOC);
+        assert!(doc.remove("user/tiler").unwrap());
+        assert!(!doc.remove("user/missing").unwrap());
+
+        assert_eq!(
+            doc.doc.to_string(),
+            r#"# my plugins
+[plugins]
+"user/bar" = { ref = "v2", name = "bar" }
+
+# keep last
+"user/clock" = "dev"
+"#
+        );
+    }
+
+    #[test]
+    fn remove_last_entry_keeps_table() {
+        let mut doc = parse("# header\n[plugins]\n\"user/only\" = \"main\"\n");
+        assert!(doc.remove("user/only").unwrap());
+
+        assert_eq!(doc.doc.to_string(), "# header\n[plugins]\n");
+        assert!(!doc.contains("user/only"));
+    }
+
+    #[test]
+    fn save_round_trips() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("plugins.toml");
+        fs::write(&path, DOC).unwrap();
+
+        let mut doc = PluginsDocument::open(&path).unwrap();
+        doc.insert("user/new", &PluginEntry::Ref("v1".into())).unwrap();
+        doc.save().unwrap();
+
+        let saved = fs::read_to_string(&path).unwrap();
+        // nothing left of the staging file
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(saved, format!("{}\"user/new\" = \"v1\"\n", DOC));
+    }
+}
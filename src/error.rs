@@ -0,0 +1,90 @@
+//! Typed failures and the process exit codes they map to.
+//!
+//! Commands return `anyhow::Result`; errors that matter for the exit code are
+//! raised as a [`Failure`] (or come from git2/ureq/toml) somewhere in the chain.
+
+use std::fmt;
+
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_CONFIG: i32 = 2;
+pub const EXIT_FETCH: i32 = 3;
+pub const EXIT_BUILD: i32 = 4;
+pub const EXIT_ABORTED: i32 = 5;
+/// 128 + SIGINT, like shells report it
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+#[derive(Debug)]
+pub enum Failure {
+    /// plugins.toml or plugins.lock is missing, invalid or inconsistent
+    Config(String),
+    /// Fetching sources or downloading a prebuilt failed
+    Fetch(String),
+    /// A prebuilt didn't match its checksum or signature, it may have been tampered with
+    Verify(String),
+    /// A build command failed, timed out or produced no artifact
+    Build(String),
+    /// The user declined a confirmation prompt
+    Aborted,
+    /// Ctrl-C was pressed
+    Interrupted,
+    /// Some plugins of an install/update run failed, `code` is their shared exit code
+    Partial { action: &'static str, failed: usize, total: usize, code: i32 },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Config(msg) | Failure::Fetch(msg) | Failure::Verify(msg) | Failure::Build(msg) => f.write_str(msg),
+            Failure::Aborted => f.write_str("aborted"),
+            Failure::Interrupted => f.write_str("interrupted"),
+            Failure::Partial { action, failed, total, .. } => {
+                write!(f, "{} of {} plugins failed to {}", failed, total, action)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl Failure {
+    /// Summarize per-plugin failures, keeping their exit code if they all agree.
+    pub fn partial(action: &'static str, failures: &[(String, anyhow::Error)], total: usize) -> Self {
+        let mut codes = failures.iter().map(|(_, e)| exit_code(e));
+        let first = codes.next().unwrap_or(EXIT_FAILURE);
+        let code = if codes.all(|c| c == first) { first } else { EXIT_FAILURE };
+
+        Failure::Partial { action, failed: failures.len(), total, code }
+    }
+}
+
+pub fn is_aborted(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<Failure>(), Some(Failure::Aborted))
+}
+
+/// Whether a prebuilt was refused because it failed a checksum or signature check.
+pub fn is_verify(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| matches!(cause.downcast_ref::<Failure>(), Some(Failure::Verify(_))))
+}
+
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    for cause in err.chain() {
+        if let Some(failure) = cause.downcast_ref::<Failure>() {
+            return match failure {
+                Failure::Config(_) => EXIT_CONFIG,
+                Failure::Fetch(_) | Failure::Verify(_) => EXIT_FETCH,
+                Failure::Build(_) => EXIT_BUILD,
+                Failure::Aborted => EXIT_ABORTED,
+                Failure::Interrupted => EXIT_INTERRUPTED,
+                Failure::Partial { code, .. } => *code,
+            };
+        }
+        if cause.is::<toml::de::Error>() {
+            return EXIT_CONFIG;
+        }
+        if cause.is::<git2::Error>() || cause.is::<ureq::Error>() {
+            return EXIT_FETCH;
+        }
+    }
+
+    EXIT_FAILURE
+}
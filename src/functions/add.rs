@@ -1,47 +1,74 @@
+use crate::editor::PluginsDocument;
+use crate::error::Failure;
 use crate::opts::AddArgs;
-use crate::schema::{PluginsFile, PluginEntry, PluginConfig};
+use crate::paths::ConfigDir;
+use crate::schema::{BuildCommand, PluginEntry, PluginConfig, PluginsFile, PrebuiltPolicy, DEFAULT_REF};
+use crate::source::PluginSource;
+use crate::versions;
 use anyhow::{Context, Result, bail};
 use colored::Colorize;
-use std::path::Path;
 use std::fs;
 
-pub fn add_plugin(args: AddArgs) -> Result<()> {
-    let toml_path = Path::new("plugins.toml");
+pub fn add_plugin(config: &ConfigDir, args: AddArgs) -> Result<()> {
+    let toml_path = &config.toml();
 
     if !toml_path.exists() {
-        bail!("plugins.toml does not exist, run 'eiipm init' first");
+        bail!(Failure::Config(format!(
+            "plugins.toml not found in {}, run 'eiipm init' first",
+            config.root().display()
+        )));
     }
 
-    let contents = fs::read_to_string(toml_path)
-        .context("failed to read plugins.toml")?;
-    let mut file: PluginsFile = toml::from_str(&contents)
-        .context("failed to parse plugins.toml")?;
+    let mut doc = PluginsDocument::open(toml_path)?;
 
-    if file.plugins.contains_key(&args.plugin) {
-        bail!("{} is already in plugins.toml", args.plugin);
+    PluginSource::parse(&args.plugin)?;
+
+    if doc.contains(&args.plugin) {
+        bail!(Failure::Config(format!("{} is already in plugins.toml", args.plugin)));
     }
 
-    let ref_ = args.ref_.unwrap_or_else(|| "main".to_string());
+    let ref_ = args.ref_.unwrap_or_else(|| DEFAULT_REF.to_string());
+    let is_requirement = versions::is_requirement(&ref_);
+    if is_requirement {
+        versions::parse(&ref_)?;
+    }
 
-    let needs_config = args.prebuilt || args.build.is_some() || args.artifact.is_some();
+    let needs_config = args.prebuilt || args.build.is_some() || args.artifact.is_some() || args.name.is_some();
 
     let entry = if needs_config {
-        PluginEntry::Config(PluginConfig {
-            ref_,
-            prebuilt: if args.prebuilt { Some(true) } else { None },
-            build: args.build,
+        PluginEntry::Config(Box::new(PluginConfig {
+            ref_: if is_requirement { None } else { Some(ref_.clone()) },
+            version: if is_requirement { Some(ref_) } else { None },
+            git: None,
+            path: None,
+            name: args.name,
+            prebuilt: if args.prebuilt { Some(PrebuiltPolicy::Prefer) } else { None },
+            build: args.build.map(BuildCommand::Line),
+            env: None,
+            workdir: None,
+            shell: None,
+            timeout: None,
             artifact: args.artifact,
-        })
+            artifacts: None,
+            pubkey: None,
+        }))
     } else {
         PluginEntry::Ref(ref_)
     };
 
-    file.plugins.insert(args.plugin.clone(), entry);
+    // two plugins with the same name would overwrite each other's artifact
+    let contents = fs::read_to_string(toml_path).context("failed to read plugins.toml")?;
+    let file: PluginsFile = toml::from_str(&contents).context("failed to parse plugins.toml")?;
+    let name = entry.name(&args.plugin)?;
+    if let Some(other) = file.plugins.iter().find(|(key, e)| e.name(key).is_ok_and(|n| n == name)).map(|(key, _)| key) {
+        bail!(Failure::Config(format!(
+            "{} would be installed as plugins/{}.so, which {} already uses\npass --name to install it under another name",
+            args.plugin, name, other
+        )));
+    }
 
-    let updated = toml::to_string_pretty(&file)
-        .context("failed to serialize plugins.toml")?;
-    fs::write(toml_path, updated)
-        .context("failed to write plugins.toml")?;
+    doc.insert(&args.plugin, &entry)?;
+    doc.save()?;
 
     log::info!("{} {} to plugins.toml", "added".green().bold(), args.plugin.cyan());
     log::info!("{} run {} to install it", "tip:".dimmed(), "eiipm install".cyan());
@@ -1,8 +1,12 @@
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use colored::Colorize;
 use dirs::cache_dir;
 use std::fs;
-use std::path::Path;
+use std::path::PathBuf;
+use crate::paths::ConfigDir;
+use crate::error::Failure;
+use crate::history::History;
+use crate::schema::LockFile;
 use crate::utils;
 
 pub fn clean_cache() -> Result<()> {
@@ -30,9 +34,8 @@ pub fn clean_cache() -> Result<()> {
         log::info!("  {}", entry.file_name().to_string_lossy().dimmed());
     }
 
-    if !utils::confirm("delete the entire eiipm cache?") {
-        log::info!("{}", "aborted".dimmed());
-        return Ok(());
+    if !utils::confirm("delete the entire eiipm cache?")? {
+        bail!(Failure::Aborted);
     }
 
     fs::remove_dir_all(&cache_root)
@@ -42,41 +45,59 @@ pub fn clean_cache() -> Result<()> {
     Ok(())
 }
 
-pub fn clean_plugins() -> Result<()> {
-    let plugins_dir = Path::new("plugins");
-    let lock_path = Path::new("plugins.lock");
+pub fn clean_plugins(config: &ConfigDir) -> Result<()> {
+    let plugins_dir = &config.plugins();
+    let lock_path = &config.lock();
 
     if !plugins_dir.exists() {
         log::info!("{}", "plugins/ does not exist, nothing to clean".dimmed());
         return Ok(());
     }
 
-    let on_disk: Vec<_> = fs::read_dir(plugins_dir)
+    let mut on_disk: Vec<PathBuf> = fs::read_dir(plugins_dir)
         .context("failed to read plugins/ dir")?
         .filter_map(|e| e.ok())
-        .filter(|e| e.path().extension().map_or(false, |x| x == "so"))
+        .map(|e| e.path())
+        .filter(|path| path.is_file() && path.extension().is_some_and(|x| x == "so"))
         .collect();
 
+    let lock: LockFile = if lock_path.exists() {
+        let contents = fs::read_to_string(lock_path).context("failed to read plugins.lock")?;
+        toml::from_str(&contents).context("failed to parse plugins.lock")?
+    } else {
+        LockFile { version: 1, plugin: vec![] }
+    };
+
+    let tracked: Vec<PathBuf> = lock
+        .plugin
+        .iter()
+        .flat_map(|p| [&p.artifact].into_iter().chain(&p.files))
+        .map(|f| config.join(f))
+        .collect();
+
+    // files in subdirectories are only candidates if an earlier version of a
+    // plugin installed them, anything else there belongs to the user
+    let cache_root = cache_dir()
+        .context("could not resolve cache directory")?
+        .join("eiipm");
+    for plugin in &lock.plugin {
+        let history = History::open(&cache_root, &plugin.repo)?;
+        for file in history.entries.iter().flat_map(|e| &e.plugin.files) {
+            let path = config.join(file);
+            if path.starts_with(plugins_dir) && path.is_file() && !on_disk.contains(&path) {
+                on_disk.push(path);
+            }
+        }
+    }
+
     if on_disk.is_empty() {
         log::info!("{}", "plugins/ is already empty".dimmed());
         return Ok(());
     }
 
-    let tracked: Vec<String> = if lock_path.exists() {
-        let contents = fs::read_to_string(lock_path).context("failed to read plugins.lock")?;
-        let lock: crate::schema::LockFile = toml::from_str(&contents)
-            .context("failed to parse plugins.lock")?;
-        lock.plugin.into_iter().map(|p| p.artifact).collect()
-    } else {
-        vec![]
-    };
-
     let untracked: Vec<_> = on_disk
         .iter()
-        .filter(|e| {
-            let path = e.path().to_string_lossy().to_string();
-            !tracked.contains(&path)
-        })
+        .filter(|path| !tracked.contains(path))
         .collect();
 
     if untracked.is_empty() {
@@ -85,18 +106,18 @@ pub fn clean_plugins() -> Result<()> {
     }
 
     log::info!("untracked artifacts:");
-    for entry in &untracked {
-        log::info!("  {}", entry.path().display().to_string().dimmed());
+    for path in &untracked {
+        log::info!("  {}", path.display().to_string().dimmed());
     }
 
-    if !utils::confirm("delete untracked artifacts?") {
-        log::info!("{}", "aborted".dimmed());
-        return Ok(());
+    if !utils::confirm("delete untracked artifacts?")? {
+        bail!(Failure::Aborted);
     }
 
-    for entry in untracked {
-        fs::remove_file(entry.path())
-            .with_context(|| format!("failed to delete {}", entry.path().display()))?;
+    for path in untracked {
+        fs::remove_file(path)
+            .with_context(|| format!("failed to delete {}", path.display()))?;
+        config.remove_empty_parents(path);
     }
 
     log::info!("{} plugins/ cleaned", "done!".green().bold());
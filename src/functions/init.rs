@@ -1,17 +1,23 @@
 use std::fs;
-use std::path::Path;
+use crate::paths::ConfigDir;
 use colored::Colorize;
 use crate::utils;
+use crate::error::Failure;
 use anyhow::{Context, Result, bail};
 
 const PLUGINS_TOML: &str = r#"# Eiipm plugins manifest
 # Add plugins in "user/repo" = "ref" format
 # ref can be a branch, tag, or commit SHA
+# keys default to GitHub, but "gitlab:user/repo", "codeberg:user/repo",
+# full git URLs and "file://" paths work too
 #
 # Examples:
 #   "user/repo" = "main"
 #   "user/repo" = "v1.0.0"
+#   "codeberg:user/repo" = "main"
+#   "https://git.example.com/user/repo.git" = "main"
 #   "user/repo" = { ref = "main", build = "make release", artifact = "build/out.so" }
+#   "my-plugin" = { path = "../my-plugin" }
 
 [plugins]
 "#;
@@ -21,14 +27,20 @@ const PLUGINS_LOCK: &str = r#"# This file is automatically @generated by eiipm.
 version = 1
 "#;
 
-pub fn init_plugin_repo() -> Result<()> {
-    let toml_path = Path::new("plugins.toml");
-    let lock_path = Path::new("plugins.lock");
+pub fn init_plugin_repo(config: &ConfigDir) -> Result<()> {
+    let toml_path = &config.toml();
+    let lock_path = &config.lock();
 
     if toml_path.exists() {
-        bail!("plugins.toml already exists, this config is already initialized");
+        bail!(Failure::Config("plugins.toml already exists, this config is already initialized".into()));
     }
 
+    fs::create_dir_all(config.root())
+        .with_context(|| format!("failed to create {}", config.root().display()))?;
+
+    // ask up front so a refused prompt doesn't leave a half initialized config
+    let gitignore = utils::confirm("Add plugins/ to .gitignore?")?;
+
     fs::write(toml_path, PLUGINS_TOML)
         .context("failed to create plugins.toml")?;
     log::info!("{} plugins.toml", "created".green().bold());
@@ -37,20 +49,20 @@ pub fn init_plugin_repo() -> Result<()> {
         .context("failed to create plugins.lock")?;
     log::info!("{} plugins.lock", "created".green().bold());
 
-    fs::create_dir_all("plugins")
+    fs::create_dir_all(config.plugins())
         .context("failed to create plugins/")?;
     log::info!("{} plugins/", "created".green().bold());
 
-    if utils::confirm("Add plugins/ to .gitignore?") {
-        add_to_gitignore()?;
+    if gitignore {
+        add_to_gitignore(config)?;
     }
 
     log::info!("\n{} run {} to install plugins", "tip:".dimmed(), "eiipm install".cyan());
     Ok(())
 }
 
-fn add_to_gitignore() -> Result<()> {
-    let gitignore_path = Path::new(".gitignore");
+fn add_to_gitignore(config: &ConfigDir) -> Result<()> {
+    let gitignore_path = &config.join(".gitignore");
     let entry = "plugins/\n";
 
     if gitignore_path.exists() {
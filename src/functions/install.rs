@@ -1,119 +1,273 @@
+use crate::artifacts;
+use crate::build::{self, BuildSpec};
+use crate::cancel;
+use crate::error::{self, Failure};
 use crate::git;
-use crate::schema::{LockFile, LockedPlugin, PluginEntry, PluginsFile, PluginManifest, PluginManifestInner};
+use crate::history::History;
+use crate::jobs::{self, DirLocks, Semaphore};
+use crate::prebuilt;
+use crate::progress::spinner;
+use crate::source::{PluginSource, SourceKind};
+use crate::transaction::{self, Transaction};
+use crate::versions;
+use crate::opts::{InstallArgs, JobsArgs};
+use crate::paths::ConfigDir;
+use crate::schema::{
+    InstallMethod, LockFile, LockedPlugin, PluginEntry, PluginsFile, PluginManifest, PluginManifestInner,
+    PrebuiltPolicy,
+};
 use anyhow::{bail, Context, Result};
 use colored::Colorize;
 use dirs::cache_dir;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::ProgressBar;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// State shared by every plugin processed during one run.
+pub struct Session {
+    pub config: ConfigDir,
+    pub cache_root: PathBuf,
+    /// Bounds how many builds run at the same time
+    pub builds: Semaphore,
+    /// Plugins with the same source share a cache dir (or local checkout) and take turns in it
+    pub caches: DirLocks,
+    /// `--build-timeout`, in seconds
+    pub build_timeout: Option<u64>,
+    /// Artifacts replaced during this run, restored unless it commits
+    pub transaction: Transaction,
+    /// Installed files and the plugin they belong to, from plugins.lock and this run
+    owners: Mutex<HashMap<PathBuf, String>>,
+}
+
+impl Session {
+    pub fn new(config: &ConfigDir, cache_root: PathBuf, jobs: &JobsArgs, lock: &LockFile) -> Self {
+        let owners = lock
+            .plugin
+            .iter()
+            .flat_map(|lp| lp.files.iter().chain([&lp.artifact]).map(|f| (config.join(f), lp.repo.clone())))
+            .collect();
+
+        Session {
+            config: config.clone(),
+            cache_root,
+            builds: Semaphore::new(jobs.build_jobs()),
+            caches: DirLocks::default(),
+            build_timeout: jobs.build_timeout,
+            transaction: Transaction::new(),
+            owners: Mutex::new(owners),
+        }
+    }
+
+    /// Where the artifact of a plugin is installed to.
+    pub fn artifact_path(&self, name: &str) -> PathBuf {
+        self.config.plugins().join(format!("{}.so", name))
+    }
 
-pub const DEFAULT_BUILD: &str = "cargo build --release";
+    /// Record that `repo` installs `dst`, refusing files that belong to
+    /// another plugin or that were put there by someone else.
+    fn claim(&self, repo: &str, dst: &Path) -> Result<()> {
+        let mut owners = self.owners.lock().unwrap_or_else(|e| e.into_inner());
+        let rel = self.config.relative(dst);
+
+        match owners.get(dst) {
+            Some(owner) if owner != repo => bail!(Failure::Config(format!(
+                "{} and {} both install {}, give them different destinations with `src -> dest`",
+                owner,
+                repo,
+                rel.display(),
+            ))),
+            Some(_) => {}
+            None if dst.exists() => bail!(Failure::Config(format!(
+                "{} already exists and wasn't installed by {}, move it away or pick another destination",
+                rel.display(),
+                repo,
+            ))),
+            None => {
+                owners.insert(dst.to_path_buf(), repo.to_string());
+            }
+        }
+        Ok(())
+    }
+}
 
-pub fn install_plugins() -> Result<()> {
-    let toml_path = Path::new("plugins.toml");
-    let lock_path = Path::new("plugins.lock");
+pub fn install_plugins(config: &ConfigDir, args: InstallArgs) -> Result<()> {
+    let toml_path = &config.toml();
+    let lock_path = &config.lock();
 
     if !toml_path.exists() {
-        bail!("plugins.toml not found, run 'eiipm init' first");
+        bail!(Failure::Config(format!(
+            "plugins.toml not found in {}, run 'eiipm init' first",
+            config.root().display()
+        )));
     }
 
     let contents = fs::read_to_string(toml_path).context("failed to read plugins.toml")?;
     let file: PluginsFile = toml::from_str(&contents).context("failed to parse plugins.toml")?;
-
-    if file.plugins.is_empty() {
-        log::info!("{}", "nothing to install".dimmed());
-        return Ok(());
-    }
+    file.check()?;
 
     //  handle lockfile
     let mut lock: LockFile = if lock_path.exists() {
         let lock_contents = fs::read_to_string(lock_path).context("failed to read plugins.lock")?;
         toml::from_str(&lock_contents).context("failed to parse plugins.lock")?
+    } else if args.locked {
+        bail!(Failure::Config("plugins.lock not found, --locked needs an existing lockfile".into()));
     } else {
         LockFile { version: 1, plugin: vec![] }
     };
 
+    if args.locked {
+        check_lock_matches(&file, &lock)?;
+    }
+
+    if file.plugins.is_empty() {
+        log::info!("{}", "nothing to install".dimmed());
+        return Ok(());
+    }
+
     let cache_root = cache_dir()
         .context("could not resolve cache directory")?
         .join("eiipm");
     fs::create_dir_all(&cache_root).context("failed to create cache dir")?;
-    fs::create_dir_all("plugins").context("failed to create plugins/ dir")?;
+    fs::create_dir_all(config.plugins()).context("failed to create plugins/ dir")?;
+
+    let jobs = args.jobs.jobs();
+    let session = Session::new(config, cache_root, &args.jobs, &lock);
 
     let total = file.plugins.len();
     log::info!("installing {} plugin{}", total, if total == 1 { "" } else { "s" });
 
-    for (repo, entry) in &file.plugins {
-        install_one(repo, entry, &cache_root, &mut lock)?;
+    let targets: Vec<(&String, &PluginEntry)> = file.plugins.iter().collect();
+    let failed = AtomicBool::new(false);
+
+    // None - not attempted because an earlier plugin failed with --fail-fast, or Ctrl-C was pressed
+    let results = jobs::run_parallel(&targets, jobs, |(repo, entry)| {
+        if (args.fail_fast && failed.load(Ordering::SeqCst)) || cancel::requested() {
+            return None;
+        }
+        let existing = lock.plugin.iter().find(|p| &p.repo == *repo);
+        let result = install_one(&session, repo, entry, existing, args.locked);
+        if result.is_err() {
+            failed.store(true, Ordering::SeqCst);
+        }
+        Some(result)
+    });
+
+    let mut installed = vec![];
+    let mut failures = vec![];
+    for ((repo, _), result) in targets.iter().zip(results) {
+        match result {
+            Some(Ok(Some(locked))) => installed.push((*repo, locked)),
+            Some(Ok(None)) | None => {}
+            Some(Err(e)) => failures.push((repo.to_string(), e)),
+        }
+    }
+
+    // --fail-fast keeps the old all-or-nothing behaviour
+    if args.fail_fast && !failures.is_empty() && !cancel::requested() {
+        let (_, e) = failures.swap_remove(0);
+        return Err(e);
+    }
+
+    let mut stale = vec![];
+    for (repo, locked) in installed {
+        remember_previous(&session, repo, &lock);
+        stale.extend(stale_files(&lock, &locked));
+        upsert_lock(&mut lock, locked);
     }
 
-    let lock_str = toml::to_string_pretty(&lock).context("failed to serialize lockfile")?;
-    fs::write(lock_path, lock_str).context("failed to write plugins.lock")?;
+    // --locked never touches the lockfile
+    if !args.locked {
+        let lock_str = toml::to_string_pretty(&lock).context("failed to serialize lockfile")?;
+        transaction::write_atomic(lock_path, lock_str).context("failed to write plugins.lock")?;
+    }
+    session.transaction.commit();
+    remove_stale(config, &stale);
+
+    cancel::check()?;
+
+    if !failures.is_empty() {
+        print_failures("install", &failures);
+        bail!(Failure::partial("install", &failures, total));
+    }
 
     log::info!("\n{} all plugins installed", "done!".green().bold());
     Ok(())
 }
 
+// Some - lock entry of the freshly installed plugin
+// None - plugin was skipped
 fn install_one(
+    session: &Session,
     repo: &str,
     entry: &PluginEntry,
-    cache_root: &Path,
-    lock: &mut LockFile,
-) -> Result<()> {
-    if lock.plugin.iter().any(|p| p.repo == repo) {
-        log::info!("{} {} {}", "-".dimmed(), repo.dimmed(), "already installed, skipping".dimmed());
-        return Ok(());
-    }
-
-    let ref_ = match entry {
-        PluginEntry::Ref(r) => r.as_str(),
-        PluginEntry::Config(c) => c.ref_.as_str(),
-    };
-    let build_override = match entry {
-        PluginEntry::Config(c) => c.build.as_deref(),
-        _ => None,
-    };
-    let artifact_override = match entry {
-        PluginEntry::Config(c) => c.artifact.as_deref(),
-        _ => None,
-    };
-    let prebuilt_requested = match entry {
-        PluginEntry::Config(c) => c.prebuilt.unwrap_or(false),
-        _ => false,
+    existing: Option<&LockedPlugin>,
+    locked: bool,
+) -> Result<Option<LockedPlugin>> {
+    // with --locked, everything is in the lockfile and only missing artifacts are installed
+    let pinned = match existing {
+        Some(lp) if locked && !session.config.join(&lp.artifact).exists() => Some(lp.sha.clone()),
+        Some(lp) if !locked => {
+            let reasons = drift_reasons(entry, lp);
+            if reasons.is_empty() {
+                log::info!("{} {} {}", "-".dimmed(), repo.dimmed(), "already installed, skipping".dimmed());
+                return Ok(None);
+            }
+            log::info!("{} {} {}", "reinstalling".yellow().bold(), repo, format!("({})", reasons.join(", ")).dimmed());
+            None
+        }
+        Some(_) => {
+            log::info!("{} {} {}", "-".dimmed(), repo.dimmed(), "already installed, skipping".dimmed());
+            return Ok(None);
+        }
+        None => None,
     };
 
-    let cache_dir = cache_root.join(repo.replace('/', "__"));
-    let repo_url = format!("https://github.com/{}.git", repo);
-    let short_name = repo.split('/').last().unwrap_or(repo);
-    let artifact_dst = PathBuf::from("plugins").join(format!("{}.so", short_name));
+    let source = entry.source(repo)?;
+    if source.kind == SourceKind::Path {
+        if let Some(sha) = &pinned {
+            let (current, _) = git::worktree_state(&session.config.join(&source.location)).unwrap_or_default();
+            if &current != sha {
+                bail!(Failure::Config(format!(
+                    "{} is at {} but plugins.lock expects {}",
+                    source.location,
+                    if current.is_empty() { "an unknown revision" } else { &current },
+                    sha,
+                )));
+            }
+        }
+        return install_from_path(session, repo, entry, &source).map(Some);
+    }
 
-    //  Using prebuilts
-    if prebuilt_requested {
-        let sp = spinner(&format!("{} {}", "fetching".cyan(), repo));
+    let cache_dir = session.cache_root.join(source.cache_key());
+    let _cache = session.caches.lock(&cache_dir);
+    let repo_url = source.url();
+    let artifact_dst = session.artifact_path(&entry.name(repo)?);
 
-        if cache_dir.exists() {
-            fs::remove_dir_all(&cache_dir)
-                .with_context(|| format!("failed to clear stale cache for {}", repo))?;
-        }
-        git::init_and_fetch(&repo_url, &cache_dir, ref_, 1)
-            .with_context(|| format!("failed to clone {}", repo))?;
+    let sp = spinner(&format!("{} {}", "fetching".cyan(), repo));
 
-        sp.finish_with_message(format!("{} {}", "fetched".green(), repo));
+    // --locked reinstalls the tag the requirement resolved to back then
+    let locked_tag = existing.filter(|_| pinned.is_some()).map(|lp| lp.ref_.as_str());
+    let entry = &*resolve_version(repo, entry, &source, locked_tag)?;
 
-        let plugin_manifest = read_plugin_manifest(&cache_dir);
+    // fetch the exact locked commit if pinned, otherwise whatever ref points to
+    let fetch_ref = match &pinned {
+        Some(sha) => sha.clone(),
+        None => fetch_ref(entry),
+    };
 
-        let prebuilt_url = plugin_manifest
-            .as_ref()
-            .and_then(|m| m.prebuilt.as_ref())
-            .map(|p| p.url.clone())
-            .with_context(|| format!("{} requested prebuilt but plugin.toml has no [plugin.prebuilt] section", repo))?;
+    fetch_fresh(repo, &repo_url, &cache_dir, &fetch_ref, pinned.as_deref())?;
 
-        let resolved_url = resolve_prebuilt_url(&prebuilt_url, ref_);
+    let sha = head_sha(&cache_dir).unwrap_or_else(|_| "unknown".to_string());
 
-        let sp = spinner(&format!("{} {} {}", "downloading".cyan(), repo, resolved_url.dimmed()));
-        download_prebuilt(&resolved_url, &artifact_dst)
-            .with_context(|| format!("failed to download prebuilt for {}", repo))?;
+    //  Using prebuilts
+    let reproduce = existing.filter(|_| locked);
+    if let Some(checksum) = try_prebuilt(session, repo, entry, reproduce, &cache_dir, &artifact_dst, &sp)? {
+        let files = install_files(session, repo, entry, &cache_dir, &artifact_dst)?;
         sp.finish_with_message(format!(
             "{} {} {}",
             "✔".green().bold(),
@@ -121,137 +275,527 @@ fn install_one(
             "(prebuilt)".dimmed(),
         ));
 
-        let sha = head_sha(&cache_dir).unwrap_or_else(|_| "unknown".to_string());
-        upsert_lock(lock, repo, ref_, &sha, &artifact_dst);
-        return Ok(());
+        let mut locked = locked_plugin(session, repo, entry, &sha, &artifact_dst);
+        locked.method = InstallMethod::Prebuilt;
+        locked.sha256 = Some(checksum);
+        locked.files = files;
+        return Ok(Some(locked));
     }
 
     // Building
-    let sp = spinner(&format!("{} {}", "fetching".cyan(), repo));
+    sp.finish_with_message(format!("{} {}", "fetched".green(), repo));
 
-    if cache_dir.exists() {
-        fs::remove_dir_all(&cache_dir)
-            .with_context(|| format!("failed to clear stale cache for {}", repo))?;
+    build_and_copy(session, repo, entry, &source, &cache_dir, &artifact_dst)?;
+    let files = install_files(session, repo, entry, &cache_dir, &artifact_dst)?;
+
+    log::info!("{} {}", "installed".green().bold(), session.config.relative(&artifact_dst).display());
+
+    let mut locked = locked_plugin(session, repo, entry, &sha, &artifact_dst);
+    locked.files = files;
+    Ok(Some(locked))
+}
+
+/// Download the prebuilt described by `src_dir`'s plugin.toml if the plugin's
+/// `prebuilt` policy asks for it and return its checksum, or None if the
+/// plugin should be built from source instead.
+///
+/// With `reproduce`, the plugin is installed the same way as that lock entry
+/// and the download must match its checksum.
+pub fn try_prebuilt(
+    session: &Session,
+    repo: &str,
+    entry: &PluginEntry,
+    reproduce: Option<&LockedPlugin>,
+    src_dir: &Path,
+    artifact_dst: &Path,
+    sp: &ProgressBar,
+) -> Result<Option<String>> {
+    let policy = match reproduce {
+        Some(lp) if lp.method == InstallMethod::Prebuilt => PrebuiltPolicy::Require,
+        Some(_) => PrebuiltPolicy::Never,
+        None => entry.config().and_then(|c| c.prebuilt).unwrap_or_default(),
+    };
+    if policy == PrebuiltPolicy::Never {
+        return Ok(None);
     }
-    git::init_and_fetch(&repo_url, &cache_dir, ref_, 1)
-        .with_context(|| format!("failed to clone {}", repo))?;
 
-    sp.finish_with_message(format!("{} {}", "fetched".green(), repo));
+    let download = || {
+        let plugin_manifest = read_plugin_manifest(src_dir);
+        let Some(prebuilt_config) = plugin_manifest.as_ref().and_then(|m| m.prebuilt.as_ref()) else {
+            bail!("plugin.toml has no [plugin.prebuilt] section");
+        };
+
+        let source = entry.source(repo)?;
+        let sha = head_sha(src_dir).unwrap_or_default();
+        let vars = prebuilt::Placeholders { version: entry.ref_(), sha: &sha, name: source.name() };
+
+        let resolved_url = prebuilt::resolve_url(prebuilt_config, &vars)?;
+        sp.set_message(format!("{} {} {}", "downloading".cyan(), repo, resolved_url.dimmed()));
+
+        prebuilt::install(
+            &session.transaction,
+            artifact_dst,
+            prebuilt_config,
+            &resolved_url,
+            &vars,
+            reproduce.and_then(|lp| lp.sha256.as_deref()),
+            entry.config().and_then(|c| c.pubkey.as_ref()),
+        )
+        .with_context(|| format!("failed to download prebuilt for {}", repo))
+    };
+
+    match download() {
+        Ok(checksum) => Ok(Some(checksum)),
+        // a download that fails verification may have been tampered with, that is never retried from source
+        Err(e) if policy == PrebuiltPolicy::Prefer && !cancel::requested() && !error::is_verify(&e) => {
+            log::warn!("{}, building from source instead", format!("{:#}", e).yellow());
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// `entry` with its version requirement resolved to the highest matching tag
+/// of the plugin's remote, or to `locked_tag` when reproducing the lockfile.
+pub fn resolve_version<'a>(
+    repo: &str,
+    entry: &'a PluginEntry,
+    source: &PluginSource,
+    locked_tag: Option<&str>,
+) -> Result<Cow<'a, PluginEntry>> {
+    let Some(req) = entry.version_req() else {
+        return Ok(Cow::Borrowed(entry));
+    };
+
+    let tag = match locked_tag {
+        Some(tag) => tag.to_string(),
+        None => versions::resolve(&source.url(), req)?,
+    };
+    log::debug!("{}: {} resolved to {}", repo, req, tag);
+    Ok(Cow::Owned(entry.resolved(&tag)))
+}
 
-    let plugin_manifest = read_plugin_manifest(&cache_dir);
+/// Build a plugin straight from a local checkout, bypassing the cache.
+pub fn install_from_path(
+    session: &Session,
+    repo: &str,
+    entry: &PluginEntry,
+    source: &PluginSource,
+) -> Result<LockedPlugin> {
+    let src_dir = session.config.join(&source.location);
+    if !src_dir.is_dir() {
+        bail!(Failure::Config(format!("{} is not a directory", src_dir.display())));
+    }
+    // entries that point at the same checkout build in it one at a time
+    let _src = session.caches.lock(&src_dir.canonicalize().unwrap_or_else(|_| src_dir.clone()));
 
-    let build_cmd = build_override
-        .or_else(|| plugin_manifest.as_ref().and_then(|m| m.build.as_deref()))
-        .unwrap_or(DEFAULT_BUILD);
+    let artifact_dst = session.artifact_path(&entry.name(repo)?);
+
+    if matches!(entry, PluginEntry::Config(c) if c.prebuilt.is_some_and(|p| !p.is_never())) {
+        log::warn!("{}: prebuilt is ignored for path sources", repo);
+    }
+
+    build_and_copy(session, repo, entry, source, &src_dir, &artifact_dst)?;
+    let files = install_files(session, repo, entry, &src_dir, &artifact_dst)?;
+    log::info!("{} {}", "installed".green().bold(), session.config.relative(&artifact_dst).display());
+
+    let (sha, dirty) = git::worktree_state(&src_dir).unwrap_or_default();
+    let mut locked = locked_plugin(session, repo, entry, &sha, &artifact_dst);
+    locked.dirty = dirty;
+    locked.files = files;
+
+    Ok(locked)
+}
+
+/// Run the build inside `src_dir` and copy the resulting artifact to `artifact_dst`.
+pub fn build_and_copy(
+    session: &Session,
+    repo: &str,
+    entry: &PluginEntry,
+    source: &PluginSource,
+    src_dir: &Path,
+    artifact_dst: &Path,
+) -> Result<()> {
+    let short_name = source.name();
+    let artifact_override = entry.config().and_then(|c| c.artifact.as_deref());
+
+    let plugin_manifest = read_plugin_manifest(src_dir);
+    let build = BuildSpec::resolve(entry, plugin_manifest.as_ref(), session.build_timeout);
 
     let artifact_rel = artifact_override
         .or_else(|| plugin_manifest.as_ref().and_then(|m| m.artifact.as_deref()))
         .map(|s| s.to_string())
         .unwrap_or_else(|| format!("target/release/lib{}.so", short_name.replace('-', "_")));
 
-    let sp = spinner(&format!("{} {}", "building".cyan(), repo));
-    run_build(build_cmd, &cache_dir)
+    let sp = spinner(&format!("{} {}", "waiting to build".dimmed(), repo));
+    let permit = session.builds.acquire();
+    sp.set_message(format!("{} {}", "building".cyan(), repo));
+    let log_path = build::log_path(&session.cache_root, repo, src_dir);
+    build.run(repo, src_dir, &log_path)
         .with_context(|| format!("build failed for {}", repo))?;
+    drop(permit);
     sp.finish_with_message(format!("{} {}", "built".green(), repo));
 
-    let artifact_src = cache_dir.join(artifact_rel);
+    let artifact_src = src_dir.join(artifact_rel);
 
     if !artifact_src.exists() {
-        bail!(
+        bail!(Failure::Build(format!(
             "artifact not found at {} after build! Check plugin.toml or pass --artifact",
             artifact_src.display()
-        );
+        )));
     }
 
-    fs::copy(&artifact_src, &artifact_dst)
+    session.transaction
+        .install(artifact_dst, |staged| {
+            fs::copy(&artifact_src, staged)?;
+            Ok(())
+        })
         .with_context(|| format!("failed to copy artifact for {}", repo))?;
 
-    log::info!("{} {}", "installed".green().bold(), artifact_dst.display());
+    Ok(())
+}
 
-    let sha = head_sha(&cache_dir).unwrap_or_else(|_| "unknown".to_string());
-    upsert_lock(lock, repo, ref_, &sha, &artifact_dst);
+/// Copy the plugin's extra `artifacts` from `src_dir` into the config directory
+/// and return where they went, relative to it.
+///
+/// This runs once `artifact_dst` is in place. If any file fails, the artifact
+/// and the files copied so far are put back as they were before the run, so
+/// the plugin is never committed half updated.
+pub fn install_files(
+    session: &Session,
+    repo: &str,
+    entry: &PluginEntry,
+    src_dir: &Path,
+    artifact_dst: &Path,
+) -> Result<Vec<String>> {
+    let mut written = vec![artifact_dst.to_path_buf()];
+    let result = copy_files(session, repo, entry, src_dir, &mut written);
+    if result.is_err() {
+        session.transaction.restore(&written);
+    }
+    result
+}
 
-    Ok(())
+fn copy_files(
+    session: &Session,
+    repo: &str,
+    entry: &PluginEntry,
+    src_dir: &Path,
+    written: &mut Vec<PathBuf>,
+) -> Result<Vec<String>> {
+    let specs = match entry.config().and_then(|c| c.artifacts.clone()) {
+        Some(specs) => specs,
+        None => read_plugin_manifest(src_dir).and_then(|m| m.artifacts).unwrap_or_default(),
+    };
+    if specs.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let default_dir = session.config.relative(&session.config.plugins().join(entry.name(repo)?));
+    let mappings = artifacts::resolve(&specs, src_dir, &default_dir)?;
+
+    let mut files = vec![];
+    for mapping in mappings {
+        let dst = session.config.join(&mapping.dst);
+        if dst == session.config.toml() || dst == session.config.lock() {
+            bail!(Failure::Config(format!("artifacts of {} can't overwrite {}", repo, mapping.dst.display())));
+        }
+        session.claim(repo, &dst)?;
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+
+        session.transaction
+            .install(&dst, |staged| {
+                fs::copy(&mapping.src, staged)?;
+                Ok(())
+            })
+            .with_context(|| format!("failed to copy {} for {}", mapping.dst.display(), repo))?;
+        written.push(dst);
+        files.push(mapping.dst.to_string_lossy().to_string());
+    }
+
+    log::debug!("{}: installed {}", repo, files.join(", "));
+    Ok(files)
 }
 
 // == Helpers ==
 
-pub fn resolve_prebuilt_url(url: &str, ref_: &str) -> String {
-    url
-        .replace("{version}", ref_)
-        .replace("{arch}", std::env::consts::ARCH)
-        .replace("{os}", std::env::consts::OS)
+/// Print a table of plugins that failed and why.
+pub fn print_failures(action: &str, failures: &[(String, anyhow::Error)]) {
+    let width = failures.iter().map(|(repo, _)| repo.len()).max().unwrap_or(0);
+
+    log::error!(
+        "failed to {} {} plugin{}:",
+        action,
+        failures.len(),
+        if failures.len() == 1 { "" } else { "s" }
+    );
+    for (repo, e) in failures {
+        log::info!("  {:<width$}  {}", repo.bold(), format!("{:#}", e).red(), width = width);
+    }
+}
+
+/// Clone `fetch_ref` into an empty cache dir, removing it again if anything goes wrong
+/// so an interrupted or failed fetch never leaves a half-populated cache behind.
+fn fetch_fresh(repo: &str, repo_url: &str, cache_dir: &Path, fetch_ref: &str, pinned: Option<&str>) -> Result<()> {
+    if cache_dir.exists() {
+        fs::remove_dir_all(cache_dir)
+            .with_context(|| format!("failed to clear stale cache for {}", repo))?;
+    }
+
+    let result = git::init_and_fetch(repo_url, cache_dir, fetch_ref, 1)
+        .with_context(|| format!("failed to clone {}", repo))
+        .and_then(|_| verify_pinned(repo, cache_dir, pinned));
+
+    if result.is_err() {
+        let _ = fs::remove_dir_all(cache_dir);
+    }
+    result
+}
+
+fn verify_pinned(repo: &str, cache_dir: &Path, pinned: Option<&str>) -> Result<()> {
+    if let Some(sha) = pinned {
+        let fetched = head_sha(cache_dir)?;
+        if fetched != sha {
+            bail!(Failure::Fetch(format!("{} resolved to {} but plugins.lock expects {}", repo, fetched, sha)));
+        }
+    }
+    Ok(())
+}
+
+/// Differences between a plugins.toml entry and what it was installed with.
+pub fn drift_reasons(entry: &PluginEntry, lp: &LockedPlugin) -> Vec<String> {
+    let config = entry.config();
+    let path = config.and_then(|c| c.path.as_deref());
+    let git = config.and_then(|c| c.git.as_deref());
+    let build = config.and_then(|c| c.build.as_ref());
+    let env = config.and_then(|c| c.env.as_ref());
+    let workdir = config.and_then(|c| c.workdir.as_deref());
+    let shell = config.and_then(|c| c.shell).unwrap_or(false);
+    let artifact = config.and_then(|c| c.artifact.as_deref());
+    let artifacts = config.and_then(|c| c.artifacts.as_ref());
+    let prebuilt = config.and_then(|c| c.prebuilt).unwrap_or_default();
+    let name = entry.name(&lp.repo).unwrap_or_default();
+    let pubkey = config.and_then(|c| c.pubkey.as_ref());
+
+    let mut reasons = vec![];
+
+    if path != lp.path.as_deref() {
+        reasons.push(format!(
+            "path {} → {}",
+            lp.path.as_deref().unwrap_or("none"),
+            path.unwrap_or("none"),
+        ));
+    } else if path.is_none() {
+        if git != lp.git.as_deref() {
+            reasons.push(format!(
+                "git {} → {}",
+                lp.git.as_deref().unwrap_or("none"),
+                git.unwrap_or("none"),
+            ));
+        }
+        let locked = match &lp.version {
+            Some(version) => ("version", version.as_str()),
+            None => ("ref", lp.ref_.as_str()),
+        };
+        let wanted = match entry.version_req() {
+            Some(req) => ("version", req),
+            None => ("ref", entry.ref_()),
+        };
+        if locked != wanted {
+            reasons.push(if locked.0 == wanted.0 {
+                format!("{} {} → {}", locked.0, locked.1, wanted.1)
+            } else {
+                // switching between a ref and a version, name both sides
+                format!("{} {} → {} {}", locked.0, locked.1, wanted.0, wanted.1)
+            });
+        }
+    }
+    if name != lp.installed_name() {
+        reasons.push(format!("name {} → {}", lp.installed_name(), name));
+    }
+    if build != lp.build.as_ref() || shell != lp.shell {
+        reasons.push("build command changed".to_string());
+    }
+    if env != lp.env.as_ref() {
+        reasons.push("build env changed".to_string());
+    }
+    if workdir != lp.workdir.as_deref() {
+        reasons.push("build workdir changed".to_string());
+    }
+    if artifact != lp.artifact_override.as_deref() {
+        reasons.push("artifact path changed".to_string());
+    }
+    if artifacts != lp.artifacts.as_ref() {
+        reasons.push("artifacts changed".to_string());
+    }
+    if prebuilt != lp.prebuilt {
+        reasons.push(format!("prebuilt {} → {}", lp.prebuilt, prebuilt));
+    }
+    if pubkey != lp.pubkey.as_ref() {
+        reasons.push("trusted keys changed".to_string());
+    }
+
+    reasons
 }
 
-pub fn download_prebuilt(url: &str, dst: &Path) -> Result<()> {
-    let response = ureq::get(url)
-        .call()
-        .with_context(|| format!("HTTP request failed for {}", url))?;
+/// Make sure plugins.toml and plugins.lock describe the same set of plugins
+/// so that `--locked` can reproduce the lockfile exactly.
+fn check_lock_matches(file: &PluginsFile, lock: &LockFile) -> Result<()> {
+    let mut problems = vec![];
 
-    if response.status() != 200 {
-        bail!("server returned HTTP {} for {}", response.status(), url);
+    for (repo, entry) in &file.plugins {
+        let Some(lp) = lock.plugin.iter().find(|p| &p.repo == repo) else {
+            problems.push(format!("{} is in plugins.toml but not in plugins.lock", repo));
+            continue;
+        };
+
+        let reasons = drift_reasons(entry, lp);
+        if !reasons.is_empty() {
+            problems.push(format!("{}: {}", repo, reasons.join(", ")));
+        } else if lp.path.is_some() {
+            if lp.dirty {
+                log::warn!("{} was locked from a dirty checkout and may not be reproducible", repo);
+            }
+        } else if lp.sha.is_empty() || lp.sha == "unknown" {
+            problems.push(format!("{}: no commit recorded in plugins.lock", repo));
+        }
     }
 
-    let mut out = fs::File::create(dst)
-        .with_context(|| format!("failed to create {}", dst.display()))?;
+    for lp in &lock.plugin {
+        if !file.plugins.contains_key(&lp.repo) {
+            problems.push(format!("{} is in plugins.lock but not in plugins.toml", lp.repo));
+        }
+    }
 
-    std::io::copy(&mut response.into_body().as_reader(), &mut out)
-        .context("failed to write downloaded binary")?;
+    if !problems.is_empty() {
+        bail!(Failure::Config(format!(
+            "plugins.toml and plugins.lock are out of sync:\n  {}\nrun 'eiipm install' without --locked to update the lockfile",
+            problems.join("\n  ")
+        )));
+    }
 
     Ok(())
 }
 
-pub fn upsert_lock(lock: &mut LockFile, repo: &str, ref_: &str, sha: &str, artifact_dst: &Path) {
+pub fn locked_plugin(
+    session: &Session,
+    repo: &str,
+    entry: &PluginEntry,
+    sha: &str,
+    artifact_dst: &Path,
+) -> LockedPlugin {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .map(|d| d.as_secs().to_string())
         .unwrap_or_default();
 
-    lock.plugin.retain(|p| p.repo != repo);
-    lock.plugin.push(LockedPlugin {
+    let config = entry.config();
+    let path = config.and_then(|c| c.path.clone());
+
+    LockedPlugin {
+        name: artifact_dst.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
         repo: repo.to_string(),
         sha: sha.to_string(),
-        ref_: ref_.to_string(),
-        artifact: artifact_dst.to_string_lossy().to_string(),
+        ref_: if path.is_some() { String::new() } else { entry.ref_().to_string() },
+        version: if path.is_some() { None } else { entry.version_req().map(str::to_string) },
+        git: if path.is_some() { None } else { config.and_then(|c| c.git.clone()) },
+        path,
+        dirty: false,
+        // stored relative so the config directory can be moved around
+        artifact: session.config.relative(artifact_dst).to_string_lossy().to_string(),
         built_at: now,
-    });
+        build: config.and_then(|c| c.build.clone()),
+        env: config.and_then(|c| c.env.clone()),
+        workdir: config.and_then(|c| c.workdir.clone()),
+        shell: config.and_then(|c| c.shell).unwrap_or(false),
+        artifact_override: config.and_then(|c| c.artifact.clone()),
+        artifacts: config.and_then(|c| c.artifacts.clone()),
+        files: vec![],
+        prebuilt: config.and_then(|c| c.prebuilt).unwrap_or_default(),
+        method: InstallMethod::Build,
+        sha256: None,
+        pubkey: config.and_then(|c| c.pubkey.clone()),
+    }
 }
 
-pub fn spinner(msg: &str) -> ProgressBar {
-    let sp = ProgressBar::new_spinner();
-    sp.set_style(
-        ProgressStyle::default_spinner()
-            .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
-            .template("{spinner:.cyan} {msg}")
-            .unwrap(),
-    );
-    sp.enable_steady_tick(Duration::from_millis(80));
-    sp.set_message(msg.to_string());
-    sp
+/// Move the artifact and files a plugin had before this run into its history, for `eiipm rollback`.
+pub fn remember_previous(session: &Session, repo: &str, lock: &LockFile) {
+    let Some(previous) = lock.plugin.iter().find(|p| p.repo == repo) else {
+        return;
+    };
+    // backed up if the new artifact took its place, or else still there
+    // until it is removed as a stale file after the run commits
+    let dst = session.config.join(&previous.artifact);
+    let artifact = session.transaction.backup_of(&dst).unwrap_or(dst);
+    if !artifact.is_file() {
+        return;
+    }
+    let files = previous_files(&session.transaction, &session.config, previous);
+
+    let result = History::open(&session.cache_root, repo).and_then(|mut history| history.push(previous, &artifact, &files));
+
+    if let Err(e) = result {
+        log::warn!("{}: failed to keep the previous version for rollback: {:#}", repo, e);
+    }
 }
 
-pub fn run_build(cmd: &str, cwd: &Path) -> Result<()> {
-    let mut parts = cmd.split_whitespace();
-    let bin = parts.next().context("build command is empty")?;
-    let args: Vec<&str> = parts.collect();
+/// Where the content of each of `previous.files` is before `transaction` commits:
+/// the backup if the file was replaced, or the file itself if it wasn't.
+pub fn previous_files(transaction: &Transaction, config: &ConfigDir, previous: &LockedPlugin) -> Vec<(String, PathBuf)> {
+    previous
+        .files
+        .iter()
+        .filter_map(|file| {
+            let dst = config.join(file);
+            let current = transaction.backup_of(&dst).unwrap_or(dst);
+            current.is_file().then(|| (file.clone(), current))
+        })
+        .collect()
+}
 
-    let debug = log::max_level() >= log::LevelFilter::Debug;
+pub fn upsert_lock(lock: &mut LockFile, locked: LockedPlugin) {
+    lock.plugin.retain(|p| p.repo != locked.repo && p.installed_name() != locked.installed_name());
+    lock.plugin.push(locked);
+}
 
-    let mut command = Command::new(bin);
-    command.args(&args).current_dir(cwd);
+/// Files the plugin installed before that `locked` no longer has, e.g. because its `name` changed.
+pub fn stale_files(lock: &LockFile, locked: &LockedPlugin) -> Vec<String> {
+    let Some(previous) = lock.plugin.iter().find(|p| p.repo == locked.repo) else {
+        return vec![];
+    };
+    let current: Vec<&String> = locked.files.iter().chain([&locked.artifact]).collect();
+    previous
+        .files
+        .iter()
+        .chain([&previous.artifact])
+        .filter(|f| !current.contains(f))
+        .cloned()
+        .collect()
+}
 
-    if !debug {
-        command.stdout(Stdio::null()).stderr(Stdio::null());
+/// Delete files left behind by renamed plugins or dropped artifacts, once the run has committed.
+pub fn remove_stale(config: &ConfigDir, stale: &[String]) {
+    for file in stale {
+        let path = config.join(file);
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                log::info!("{} {}", "deleted".green().bold(), file.dimmed());
+                config.remove_empty_parents(&path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("failed to delete {}: {}", file, e),
+        }
     }
+}
 
-    let status = command
-        .status()
-        .with_context(|| format!("failed to spawn '{}'", cmd))?;
-
-    if !status.success() {
-        bail!("command '{}' exited with {}", cmd, status);
+/// What to fetch for `entry`, resolved versions are always tags.
+pub fn fetch_ref(entry: &PluginEntry) -> String {
+    match entry.version_req() {
+        Some(_) => format!("refs/tags/{}", entry.ref_()),
+        None => entry.ref_().to_string(),
     }
-    Ok(())
 }
 
 pub fn head_sha(repo_path: &Path) -> Result<String> {
@@ -267,3 +811,137 @@ pub fn read_plugin_manifest(cache_dir: &Path) -> Option<PluginManifestInner> {
     let manifest: PluginManifest = toml::from_str(&contents).ok()?;
     Some(manifest.plugin)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugins(entries: &str) -> PluginsFile {
+        toml::from_str(&format!("[plugins]\n{}", entries)).unwrap()
+    }
+
+    fn entry(value: &str) -> PluginEntry {
+        plugins(&format!("\"user/tiler\" = {}", value)).plugins.swap_remove("user/tiler").unwrap()
+    }
+
+    /// Lock entry of `user/tiler` installed as plugins/tiler.so, with `fields` on top.
+    fn locked(fields: &str) -> LockedPlugin {
+        toml::from_str(&format!(
+            "repo = \"user/tiler\"\nartifact = \"plugins/tiler.so\"\nbuilt_at = \"0\"\nsha = \"abc123\"\n{}",
+            fields
+        ))
+        .unwrap()
+    }
+
+    /// `(name, fields)` of a plugins.lock entry, see [`lock`]
+    type Locked<'a> = (&'a str, &'a str);
+
+    /// Lockfile with a `user/<name>` entry installed as plugins/<name>.so for each `(name, fields)`.
+    fn lock(plugins: &[Locked]) -> LockFile {
+        let mut contents = "version = 1\n".to_string();
+        for (name, fields) in plugins {
+            contents.push_str(&format!(
+                "[[plugin]]\nrepo = \"user/{}\"\nartifact = \"plugins/{}.so\"\nbuilt_at = \"0\"\n{}\n",
+                name, name, fields
+            ));
+        }
+        toml::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn drift_by_field() {
+        let cases: &[(&str, &str, &[&str])] = &[
+            // in sync
+            ("\"main\"", "ref = \"main\"", &[]),
+            ("{ name = \"tiler\" }", "ref = \"main\"", &[]),
+            ("\"^1.2\"", "ref = \"v1.2.0\"\nversion = \"^1.2\"", &[]),
+            ("{ path = \"../tiler\" }", "path = \"../tiler\"", &[]),
+            // ref and version
+            ("\"dev\"", "ref = \"main\"", &["ref main → dev"]),
+            ("\"^1.3\"", "ref = \"v1.2.0\"\nversion = \"^1.2\"", &["version ^1.2 → ^1.3"]),
+            ("\"v1.2.0\"", "ref = \"v1.2.0\"\nversion = \"^1.2\"", &["version ^1.2 → ref v1.2.0"]),
+            ("{ version = \"^1\" }", "ref = \"main\"", &["ref main → version ^1"]),
+            // path replaces the ref comparison
+            ("{ path = \"../tiler\" }", "ref = \"main\"", &["path none → ../tiler"]),
+            ("\"main\"", "path = \"../tiler\"", &["path ../tiler → none"]),
+            ("{ path = \"../new\", name = \"tiler\" }", "path = \"../tiler\"", &["path ../tiler → ../new"]),
+            // git override, only compared for fetched plugins
+            ("{ git = \"https://codeberg.org/fork/tiler\" }", "ref = \"main\"", &["git none → https://codeberg.org/fork/tiler"]),
+            ("\"main\"", "ref = \"main\"\ngit = \"https://codeberg.org/fork/tiler\"", &["git https://codeberg.org/fork/tiler → none"]),
+            (
+                "{ git = \"https://gitlab.com/user/tiler\", ref = \"dev\" }",
+                "ref = \"main\"\ngit = \"https://codeberg.org/fork/tiler\"",
+                &["git https://codeberg.org/fork/tiler → https://gitlab.com/user/tiler", "ref main → dev"],
+            ),
+            ("{ git = \"https://codeberg.org/fork/tiler\" }", "ref = \"main\"\ngit = \"https://codeberg.org/fork/tiler\"", &[]),
+            // name
+            ("{ name = \"bar\" }", "ref = \"main\"", &["name tiler → bar"]),
+            ("\"main\"", "ref = \"main\"\nname = \"bar\"", &["name bar → tiler"]),
+            // build settings, set on either side only or changed
+            ("{ build = \"make\" }", "ref = \"main\"", &["build command changed"]),
+            ("\"main\"", "ref = \"main\"\nbuild = \"make\"", &["build command changed"]),
+            ("{ build = [\"make\"] }", "ref = \"main\"\nbuild = \"make\"", &["build command changed"]),
+            ("{ build = \"make\", shell = true }", "ref = \"main\"\nbuild = \"make\"", &["build command changed"]),
+            ("{ build = \"make\", shell = false }", "ref = \"main\"\nbuild = \"make\"", &[]),
+            ("{ env = { CC = \"clang\" } }", "ref = \"main\"", &["build env changed"]),
+            ("{ env = { CC = \"clang\" } }", "ref = \"main\"\nenv = { CC = \"gcc\" }", &["build env changed"]),
+            ("{ workdir = \"crates/x\" }", "ref = \"main\"", &["build workdir changed"]),
+            ("\"main\"", "ref = \"main\"\nworkdir = \"crates/x\"", &["build workdir changed"]),
+            ("{ artifact = \"out/x.so\" }", "ref = \"main\"", &["artifact path changed"]),
+            ("\"main\"", "ref = \"main\"\nartifact_override = \"out/x.so\"", &["artifact path changed"]),
+            ("{ artifacts = [\"a.yuck\"] }", "ref = \"main\"", &["artifacts changed"]),
+            ("{ artifacts = [\"a.yuck\"] }", "ref = \"main\"\nartifacts = [\"b.yuck\"]", &["artifacts changed"]),
+            // prebuilt and keys
+            ("{ prebuilt = \"prefer\" }", "ref = \"main\"", &["prebuilt never → prefer"]),
+            ("{ prebuilt = false }", "ref = \"main\"\nprebuilt = \"require\"", &["prebuilt require → never"]),
+            ("{ pubkey = \"RWQ\" }", "ref = \"main\"", &["trusted keys changed"]),
+            ("{ pubkey = [\"RWQ\"] }", "ref = \"main\"\npubkey = \"RWQ\"", &["trusted keys changed"]),
+            // several at once
+            (
+                "{ ref = \"dev\", name = \"bar\", build = \"make\" }",
+                "ref = \"main\"",
+                &["ref main → dev", "name tiler → bar", "build command changed"],
+            ),
+        ];
+
+        for (value, fields, expected) in cases {
+            assert_eq!(drift_reasons(&entry(value), &locked(fields)), *expected, "{} vs {:?}", value, fields);
+        }
+    }
+
+    #[test]
+    fn lock_matches() {
+        let file = plugins("\"user/a\" = \"main\"\n\"user/b\" = { path = \"../b\" }\n");
+        let lock = lock(&[("a", "ref = \"main\"\nsha = \"abc123\""), ("b", "path = \"../b\"")]);
+        check_lock_matches(&file, &lock).unwrap();
+    }
+
+    #[test]
+    fn lock_mismatches_are_rejected() {
+        let installed = ("a", "ref = \"main\"\nsha = \"abc123\"");
+        let cases: &[(&str, &[Locked], &str)] = &[
+            ("\"user/a\" = \"main\"", &[], "user/a is in plugins.toml but not in plugins.lock"),
+            ("\"user/a\" = \"main\"", &[installed, ("old", "ref = \"main\"")], "user/old is in plugins.lock but not in plugins.toml"),
+            ("\"user/a\" = \"dev\"", &[installed], "user/a: ref main → dev"),
+            // a lock fetched from another repository can't reproduce this one
+            (
+                "\"user/a\" = { git = \"https://codeberg.org/fork/a\" }",
+                &[installed],
+                "user/a: git none → https://codeberg.org/fork/a",
+            ),
+            (
+                "\"user/a\" = { git = \"https://codeberg.org/fork/a\" }",
+                &[("a", "ref = \"main\"\nsha = \"abc123\"\ngit = \"https://gitlab.com/other/a\"")],
+                "user/a: git https://gitlab.com/other/a → https://codeberg.org/fork/a",
+            ),
+            ("\"user/a\" = \"main\"", &[("a", "ref = \"main\"")], "user/a: no commit recorded in plugins.lock"),
+            ("\"user/a\" = \"main\"", &[("a", "ref = \"main\"\nsha = \"unknown\"")], "user/a: no commit recorded in plugins.lock"),
+        ];
+
+        for (entries, locked, problem) in cases {
+            let err = check_lock_matches(&plugins(entries), &lock(locked)).unwrap_err();
+            assert!(err.to_string().contains(&format!("\n  {}\n", problem)), "{}", err);
+            assert_eq!(error::exit_code(&err), error::EXIT_CONFIG);
+        }
+    }
+}
@@ -1,15 +1,19 @@
 use anyhow::{Context, Result, bail};
 use colored::Colorize;
 use std::fs;
-use std::path::Path;
+use crate::paths::ConfigDir;
 use crate::schema::{LockFile, PluginsFile};
+use crate::error::Failure;
 
-pub fn list_plugins() -> Result<()> {
-    let toml_path = Path::new("plugins.toml");
-    let lock_path = Path::new("plugins.lock");
+pub fn list_plugins(config: &ConfigDir) -> Result<()> {
+    let toml_path = &config.toml();
+    let lock_path = &config.lock();
 
     if !toml_path.exists() {
-        bail!("plugins.toml not found, run 'eiipm init' first");
+        bail!(Failure::Config(format!(
+            "plugins.toml not found in {}, run 'eiipm init' first",
+            config.root().display()
+        )));
     }
 
     let toml_contents = fs::read_to_string(toml_path).context("failed to read plugins.toml")?;
@@ -30,25 +34,39 @@ pub fn list_plugins() -> Result<()> {
     log::info!("{} plugins\n", file.plugins.len().to_string().cyan().bold());
 
     for (repo, entry) in &file.plugins {
-        let ref_ = match entry {
-            crate::schema::PluginEntry::Ref(r) => r.as_str(),
-            crate::schema::PluginEntry::Config(c) => c.ref_.as_str(),
-        };
-
         let locked = lock.as_ref().and_then(|l| l.plugin.iter().find(|p| &p.repo == repo));
 
+        let ref_ = match (entry.config().and_then(|c| c.path.as_deref()), entry.version_req()) {
+            (Some(path), _) => path.to_string(),
+            // show which tag the requirement resolved to
+            (None, Some(req)) => match locked.filter(|lp| lp.version.as_deref() == Some(req)) {
+                Some(lp) => format!("{} → {}", req, lp.ref_),
+                None => req.to_string(),
+            },
+            (None, None) => entry.ref_().to_string(),
+        };
+        let alias = match entry.config().and_then(|c| c.name.as_deref()) {
+            Some(name) => format!(" as {}", name).dimmed().to_string(),
+            None => String::new(),
+        };
+
         match locked {
             Some(lp) => {
-                let short_sha = &lp.sha[..8.min(lp.sha.len())];
-                let artifact_exists = Path::new(&lp.artifact).exists();
+                let short_sha = match &lp.path {
+                    Some(_) if lp.sha.is_empty() => "path".to_string(),
+                    Some(_) if lp.dirty => format!("{}+dirty", &lp.sha[..8.min(lp.sha.len())]),
+                    _ => lp.sha[..8.min(lp.sha.len())].to_string(),
+                };
+                let artifact_exists = config.join(&lp.artifact).exists();
                 let status = if artifact_exists {
                     "installed".green().bold()
                 } else {
                     "missing artifact".yellow().bold()
                 };
                 log::info!(
-                    "  {} {} {} {}",
+                    "  {}{} {} {} {}",
                     repo.white().bold(),
+                    alias,
                     format!("({})", ref_).dimmed(),
                     format!("@ {}", short_sha).dimmed(),
                     status,
@@ -56,8 +74,9 @@ pub fn list_plugins() -> Result<()> {
             }
             None => {
                 log::info!(
-                    "  {} {} {}",
+                    "  {}{} {} {}",
                     repo.white().bold(),
+                    alias,
                     format!("({})", ref_).dimmed(),
                     "not installed".red().bold(),
                 );
@@ -0,0 +1,45 @@
+use anyhow::{Context, Result, bail};
+use colored::Colorize;
+use dirs::cache_dir;
+use std::fs;
+use std::io::Write;
+use crate::build;
+use crate::paths::ConfigDir;
+use crate::schema::PluginsFile;
+use crate::error::Failure;
+
+pub fn show_logs(config: &ConfigDir, plugin: String) -> Result<()> {
+    let toml_path = &config.toml();
+
+    if !toml_path.exists() {
+        bail!(Failure::Config(format!(
+            "plugins.toml not found in {}, run 'eiipm init' first",
+            config.root().display()
+        )));
+    }
+
+    let toml_contents = fs::read_to_string(toml_path).context("failed to read plugins.toml")?;
+    let file: PluginsFile = toml::from_str(&toml_contents).context("failed to parse plugins.toml")?;
+
+    if !file.plugins.contains_key(&plugin) {
+        bail!(Failure::Config(format!("'{}' is not in plugins.toml", plugin)));
+    }
+
+    let cache_root = cache_dir()
+        .context("could not resolve cache directory")?
+        .join("eiipm");
+
+    let Some(log_path) = build::latest_log(&cache_root, &plugin) else {
+        bail!("no build logs for '{}', it hasn't been built since the cache was cleaned", plugin);
+    };
+
+    let contents = fs::read(&log_path)
+        .with_context(|| format!("failed to read {}", log_path.display()))?;
+
+    log::info!("{} {}", "build log".dimmed(), log_path.display().to_string().dimmed());
+    std::io::stdout()
+        .write_all(&contents)
+        .context("failed to write build log")?;
+
+    Ok(())
+}
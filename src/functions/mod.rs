@@ -5,3 +5,6 @@ pub mod clean;
 pub mod remove;
 pub mod list;
 pub mod update;
+pub mod logs;
+pub mod rollback;
+pub mod outdated;
@@ -0,0 +1,257 @@
+use anyhow::{Context, Result, bail};
+use colored::{ColoredString, Colorize};
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use crate::cancel;
+use crate::error::Failure;
+use crate::functions::install::print_failures;
+use crate::git;
+use crate::jobs;
+use crate::opts::OutdatedArgs;
+use crate::paths::ConfigDir;
+use crate::schema::{LockFile, LockedPlugin, PluginEntry, PluginsFile};
+use crate::source::SourceKind;
+use crate::versions;
+
+/// One row of the report, also its `--json` form.
+#[derive(Serialize)]
+struct Outdated {
+    plugin: String,
+    /// `ref` or version requirement from plugins.toml
+    #[serde(rename = "ref")]
+    ref_: String,
+    /// What plugins.lock has installed
+    current: Option<Revision>,
+    /// What `eiipm update` would install
+    wanted: Option<Revision>,
+    /// Newest version tag of the remote, for plugins following tags
+    latest: Option<Revision>,
+    /// `eiipm update` would change the plugin
+    outdated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Clone, PartialEq)]
+struct Revision {
+    #[serde(rename = "ref")]
+    ref_: String,
+    sha: String,
+    /// `ref_` is a tag, shown instead of the commit
+    #[serde(skip)]
+    tagged: bool,
+}
+
+impl Revision {
+    fn label(&self) -> String {
+        if self.tagged { self.ref_.clone() } else { self.sha[..8.min(self.sha.len())].to_string() }
+    }
+}
+
+pub fn outdated_plugins(config: &ConfigDir, args: OutdatedArgs) -> Result<()> {
+    let toml_path = &config.toml();
+    let lock_path = &config.lock();
+
+    if !toml_path.exists() {
+        bail!(Failure::Config(format!(
+            "plugins.toml not found in {}, run 'eiipm init' first",
+            config.root().display()
+        )));
+    }
+
+    let toml_contents = fs::read_to_string(toml_path).context("failed to read plugins.toml")?;
+    let file: PluginsFile = toml::from_str(&toml_contents).context("failed to parse plugins.toml")?;
+
+    let lock: LockFile = if lock_path.exists() {
+        let lock_contents = fs::read_to_string(lock_path).context("failed to read plugins.lock")?;
+        toml::from_str(&lock_contents).context("failed to parse plugins.lock")?
+    } else {
+        LockFile { version: 1, plugin: vec![] }
+    };
+
+    let targets: Vec<(&String, &PluginEntry)> = match &args.plugin {
+        Some(name) => match file.plugins.get_key_value(name) {
+            Some((k, v)) => vec![(k, v)],
+            None => bail!(Failure::Config(format!("'{}' is not in plugins.toml", name))),
+        },
+        None => file.plugins.iter().collect(),
+    };
+
+    // local checkouts have no remote to compare against
+    let targets: Vec<(&String, &PluginEntry)> = targets
+        .into_iter()
+        .filter(|(repo, entry)| entry.source(repo).map_or(true, |s| s.kind != SourceKind::Path))
+        .collect();
+
+    // None - not checked because Ctrl-C was pressed
+    let results = jobs::run_parallel(&targets, args.jobs(), |(repo, entry)| {
+        if cancel::requested() {
+            return None;
+        }
+        let locked = lock.plugin.iter().find(|p| &p.repo == *repo);
+        Some(check_one(repo, entry, locked))
+    });
+    cancel::check()?;
+
+    let mut rows = vec![];
+    let mut failures = vec![];
+    for ((repo, entry), result) in targets.iter().zip(results.into_iter().flatten()) {
+        match result {
+            Ok(row) => rows.push(row),
+            Err(e) => {
+                let locked = lock.plugin.iter().find(|p| &p.repo == *repo);
+                rows.push(Outdated {
+                    plugin: repo.to_string(),
+                    ref_: spec(entry).to_string(),
+                    current: locked.map(current),
+                    wanted: None,
+                    latest: None,
+                    outdated: false,
+                    error: Some(format!("{:#}", e)),
+                });
+                failures.push((repo.to_string(), e));
+            }
+        }
+    }
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&rows).context("failed to serialize report")?;
+        writeln!(io::stdout(), "{}", json).context("failed to write report")?;
+    } else {
+        print_table(&rows);
+    }
+
+    if !failures.is_empty() {
+        if !args.json {
+            print_failures("check", &failures);
+        }
+        bail!(Failure::partial("check", &failures, targets.len()));
+    }
+
+    Ok(())
+}
+
+fn check_one(repo: &str, entry: &PluginEntry, locked: Option<&LockedPlugin>) -> Result<Outdated> {
+    let source = entry.source(repo)?;
+    let refs = git::ls_remote(&source.url()).with_context(|| format!("failed to query {}", source.url()))?;
+    let tags = git::tags(&refs);
+
+    let wanted = match entry.version_req() {
+        Some(req) => {
+            let tag = versions::pick(req, &tags)?;
+            tag_revision(&refs, tag)
+        }
+        None => resolve_ref(&refs, entry.ref_())?,
+    };
+
+    // tags that look like versions can be compared with newer ones
+    let latest = if entry.version_req().is_some() || (wanted.tagged && versions::tag_version(&wanted.ref_).is_some()) {
+        versions::latest(&tags).map(|tag| tag_revision(&refs, tag))
+    } else {
+        None
+    };
+
+    let current = locked.map(current);
+    let outdated = current.as_ref().is_none_or(|c| c.sha != wanted.sha);
+
+    Ok(Outdated {
+        plugin: repo.to_string(),
+        ref_: spec(entry).to_string(),
+        current,
+        wanted: Some(wanted),
+        latest,
+        outdated,
+        error: None,
+    })
+}
+
+/// The commit `ref_` points to on the remote: a branch, a tag, or a commit itself.
+fn resolve_ref(refs: &IndexMap<String, String>, ref_: &str) -> Result<Revision> {
+    if let Some(sha) = refs.get(&format!("refs/heads/{}", ref_)).or_else(|| refs.get(ref_)) {
+        return Ok(Revision { ref_: ref_.to_string(), sha: sha.clone(), tagged: false });
+    }
+    if refs.contains_key(&format!("refs/tags/{}", ref_)) {
+        return Ok(tag_revision(refs, ref_));
+    }
+    // commits can't move, they are always up to date
+    if ref_.len() >= 7 && ref_.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(Revision { ref_: ref_.to_string(), sha: ref_.to_string(), tagged: false });
+    }
+    bail!(Failure::Fetch(format!("the remote has no branch or tag named {}", ref_)))
+}
+
+fn tag_revision(refs: &IndexMap<String, String>, tag: &str) -> Revision {
+    let sha = refs.get(&format!("refs/tags/{}", tag)).cloned().unwrap_or_default();
+    Revision { ref_: tag.to_string(), sha, tagged: true }
+}
+
+fn current(lp: &LockedPlugin) -> Revision {
+    let tagged = lp.version.is_some() || versions::tag_version(&lp.ref_).is_some();
+    Revision { ref_: lp.ref_.clone(), sha: lp.sha.clone(), tagged }
+}
+
+fn spec(entry: &PluginEntry) -> &str {
+    entry.version_req().unwrap_or(entry.ref_())
+}
+
+fn print_table(rows: &[Outdated]) {
+    let rows: Vec<&Outdated> = rows.iter().filter(|r| r.error.is_none()).collect();
+    if rows.is_empty() {
+        log::info!("{}", "no plugins to check".dimmed());
+        return;
+    }
+
+    let cells: Vec<[String; 5]> = rows
+        .iter()
+        .map(|r| {
+            let label = |rev: &Option<Revision>| rev.as_ref().map(Revision::label).unwrap_or_else(|| "-".to_string());
+            [r.plugin.clone(), r.ref_.clone(), label(&r.current), label(&r.wanted), label(&r.latest)]
+        })
+        .collect();
+
+    let header = ["plugin", "ref", "current", "wanted", "latest"];
+    let mut widths = header.map(str::len);
+    for row in &cells {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let pad = |text: &str, i: usize| format!("{:<width$}", text, width = widths[i]);
+    log::info!(
+        "  {}  {}  {}  {}  {}",
+        pad(header[0], 0).bold(),
+        pad(header[1], 1).bold(),
+        pad(header[2], 2).bold(),
+        pad(header[3], 3).bold(),
+        header[4].bold(),
+    );
+
+    for (row, cells) in rows.iter().zip(&cells) {
+        let wanted: ColoredString = if row.outdated { pad(&cells[3], 3).green() } else { pad(&cells[3], 3).dimmed() };
+        // a newer version than the requirement allows
+        let latest = if row.latest.is_some() && row.latest != row.wanted { cells[4].yellow() } else { cells[4].dimmed() };
+        log::info!(
+            "  {}  {}  {}  {}  {}",
+            pad(&cells[0], 0).white().bold(),
+            pad(&cells[1], 1).dimmed(),
+            pad(&cells[2], 2),
+            wanted,
+            latest,
+        );
+    }
+
+    let outdated = rows.iter().filter(|r| r.outdated).count();
+    if outdated == 0 {
+        log::info!("\n{} all plugins are up to date", "done!".green().bold());
+    } else {
+        log::info!(
+            "\n{} plugin{} can be updated, run {} to apply",
+            outdated.to_string().cyan().bold(),
+            if outdated == 1 { "" } else { "s" },
+            "eiipm update".cyan(),
+        );
+    }
+}
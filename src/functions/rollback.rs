@@ -0,0 +1,119 @@
+use anyhow::{Context, Result, bail};
+use colored::Colorize;
+use dirs::cache_dir;
+use std::fs;
+use crate::history::{self, History};
+use crate::paths::ConfigDir;
+use crate::schema::{LockFile, PluginsFile};
+use crate::error::Failure;
+use crate::transaction::{self, Transaction};
+use crate::functions::install::{previous_files, remove_stale, stale_files, upsert_lock};
+
+pub fn rollback_plugin(config: &ConfigDir, plugin: String, to: Option<String>) -> Result<()> {
+    let toml_path = &config.toml();
+    let lock_path = &config.lock();
+
+    if !toml_path.exists() {
+        bail!(Failure::Config(format!(
+            "plugins.toml not found in {}, run 'eiipm init' first",
+            config.root().display()
+        )));
+    }
+
+    let toml_contents = fs::read_to_string(toml_path).context("failed to read plugins.toml")?;
+    let file: PluginsFile = toml::from_str(&toml_contents).context("failed to parse plugins.toml")?;
+
+    if !file.plugins.contains_key(&plugin) {
+        bail!(Failure::Config(format!("'{}' is not in plugins.toml", plugin)));
+    }
+
+    if !lock_path.exists() {
+        bail!(Failure::Config("plugins.lock not found, run 'eiipm install' first".into()));
+    }
+
+    let lock_contents = fs::read_to_string(lock_path).context("failed to read plugins.lock")?;
+    let mut lock: LockFile = toml::from_str(&lock_contents).context("failed to parse plugins.lock")?;
+
+    let Some(current) = lock.plugin.iter().find(|p| p.repo == plugin).cloned() else {
+        bail!(Failure::Config(format!("'{}' is not installed, run 'eiipm install' first", plugin)));
+    };
+
+    let cache_root = cache_dir()
+        .context("could not resolve cache directory")?
+        .join("eiipm");
+    let mut history = History::open(&cache_root, &plugin)?;
+
+    let Some(target) = history.take(to.as_deref()) else {
+        match to {
+            Some(sha) => bail!("no earlier version of '{}' at {} was kept", plugin, sha),
+            None => bail!("no earlier version of '{}' was kept", plugin),
+        }
+    };
+    let kept = history.path(&target);
+
+    let tx = Transaction::new();
+    let artifact_dst = config.join(&target.plugin.artifact);
+    tx.install(&artifact_dst, |staged| {
+        fs::copy(&kept, staged)?;
+        Ok(())
+    })?;
+    let files_dir = history.files_dir(&target);
+    for file in &target.plugin.files {
+        let kept_file = files_dir.join(file);
+        if !kept_file.is_file() {
+            log::warn!("{} of this version was not kept, it won't be restored", file);
+            continue;
+        }
+        let dst = config.join(file);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        tx.install(&dst, |staged| {
+            fs::copy(&kept_file, staged)?;
+            Ok(())
+        })?;
+    }
+
+    // files only the newer version had
+    let stale = stale_files(&lock, &target.plugin);
+    let previous_files = previous_files(&tx, config, &current);
+    upsert_lock(&mut lock, target.plugin.clone());
+    let updated_lock = toml::to_string_pretty(&lock).context("failed to serialize lockfile")?;
+    transaction::write_atomic(lock_path, updated_lock).context("failed to write plugins.lock")?;
+
+    // the current version becomes part of the history so the rollback can be
+    // undone; its artifact was backed up if the target has the same name, or
+    // else is still in place until the stale files go
+    let current_dst = config.join(&current.artifact);
+    let current_artifact = tx.backup_of(&current_dst).unwrap_or(current_dst);
+    if current_artifact.is_file()
+        && let Err(e) = history.push(&current, &current_artifact, &previous_files)
+    {
+        log::warn!("failed to keep {} for rollback: {:#}", history::short(&current.sha), e);
+    }
+    tx.commit();
+
+    // only now that the target is in place for good
+    history.remove_copies(&target);
+    if let Err(e) = history.save() {
+        log::warn!("failed to update the history of {}: {:#}", plugin, e);
+    }
+    remove_stale(config, &stale);
+
+    log::info!(
+        "{} {} {} {}",
+        "rolled back".green().bold(),
+        plugin.cyan(),
+        history::short(&current.sha).dimmed(),
+        format!("→ {}", history::short(&target.plugin.sha)).green(),
+    );
+    if current.path.is_none() {
+        log::info!(
+            "{} set {} in plugins.toml to keep it at this version",
+            "tip:".dimmed(),
+            format!("ref = \"{}\"", target.plugin.sha).cyan()
+        );
+    }
+
+    Ok(())
+}
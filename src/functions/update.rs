@@ -2,25 +2,57 @@ use anyhow::{Context, Result, bail};
 use colored::Colorize;
 use dirs::cache_dir;
 use std::fs;
+use std::mem;
 use std::path::Path;
-use crate::schema::{LockFile, PluginsFile, PluginEntry};
+use crate::paths::ConfigDir;
+use std::time::UNIX_EPOCH;
+use crate::error::Failure;
+use crate::schema::{InstallMethod, LockFile, LockedPlugin, PluginsFile, PluginEntry};
 use crate::functions::install::{
-    head_sha, spinner, read_plugin_manifest,
-    resolve_prebuilt_url, download_prebuilt,
-    DEFAULT_BUILD
+    head_sha, fetch_ref, try_prebuilt, resolve_version,
+    install_from_path, build_and_copy, locked_plugin, remember_previous, upsert_lock, stale_files, remove_stale,
+    install_files, read_plugin_manifest, print_failures, Session
 };
+use crate::cancel;
+use crate::changelog;
 use crate::git;
+use crate::jobs;
+use crate::opts::JobsArgs;
+use crate::progress::spinner;
+use crate::source::{PluginSource, SourceKind};
+use crate::transaction;
+use crate::utils;
+
+/// Commits fetched by `update`, so the ones since the installed version can be listed
+const HISTORY_DEPTH: i32 = 50;
+/// Commits listed per plugin in the update summary
+const MAX_COMMITS: usize = 15;
+/// Changelog lines shown per plugin in the update summary
+const MAX_CHANGELOG_LINES: usize = 30;
+
+enum Outcome {
+    Updated(Box<LockedPlugin>),
+    UpToDate,
+    /// The resolved tag moved but points at the installed commit, only the lock changes
+    Retagged(Box<LockedPlugin>),
+    /// Rejected at the `--interactive` prompt
+    Declined,
+}
 
-pub fn update_plugins(maybe_plugin: Option<String>) -> Result<()> {
-    let toml_path = Path::new("plugins.toml");
-    let lock_path = Path::new("plugins.lock");
+pub fn update_plugins(config: &ConfigDir, maybe_plugin: Option<String>, interactive: bool, jobs: JobsArgs) -> Result<()> {
+    let toml_path = &config.toml();
+    let lock_path = &config.lock();
 
     if !toml_path.exists() {
-        bail!("plugins.toml not found, run 'eiipm init' first");
+        bail!(Failure::Config(format!(
+            "plugins.toml not found in {}, run 'eiipm init' first",
+            config.root().display()
+        )));
     }
 
     let toml_contents = fs::read_to_string(toml_path).context("failed to read plugins.toml")?;
     let file: PluginsFile = toml::from_str(&toml_contents).context("failed to parse plugins.toml")?;
+    file.check()?;
 
     if file.plugins.is_empty() {
         log::info!("{}", "no plugins declared in plugins.toml".dimmed());
@@ -31,18 +63,19 @@ pub fn update_plugins(maybe_plugin: Option<String>) -> Result<()> {
         let lock_contents = fs::read_to_string(lock_path).context("failed to read plugins.lock")?;
         toml::from_str(&lock_contents).context("failed to parse plugins.lock")?
     } else {
-        bail!("plugins.lock not found, run 'eiipm install' first");
+        bail!(Failure::Config("plugins.lock not found, run 'eiipm install' first".into()));
     };
 
     let cache_root = cache_dir()
         .context("could not resolve cache directory")?
         .join("eiipm");
+    let session = Session::new(config, cache_root, &jobs, &lock);
 
     let targets: Vec<(&String, &PluginEntry)> = match &maybe_plugin {
         Some(name) => {
             match file.plugins.get_key_value(name) {
                 Some((k, v)) => vec![(k, v)],
-                None => bail!("'{}' is not in plugins.toml", name),
+                None => bail!(Failure::Config(format!("'{}' is not in plugins.toml", name))),
             }
         }
         None => file.plugins.iter().collect(),
@@ -57,101 +90,181 @@ pub fn update_plugins(maybe_plugin: Option<String>) -> Result<()> {
 
     let mut updated = 0;
     let mut skipped = 0;
+    let mut declined = 0;
+    let mut failures = vec![];
+    let mut stale = vec![];
+
+    // prompts can't be answered for several plugins at once
+    let parallel = if interactive { 1 } else { jobs.jobs() };
 
-    for (repo, entry) in targets {
-        match update_one(repo, entry, &cache_root, &mut lock) {
-            Ok(true)  => updated += 1,
-            Ok(false) => skipped += 1,
-            Err(e)    => log::warn!("{} {}: {}", "failed to update".yellow().bold(), repo, e),
+    // None - not attempted because Ctrl-C was pressed
+    let results = jobs::run_parallel(&targets, parallel, |(repo, entry)| {
+        if cancel::requested() {
+            return None;
+        }
+        let existing = lock.plugin.iter().find(|p| &p.repo == *repo);
+        Some(update_one(&session, repo, entry, existing, interactive))
+    });
+
+    for ((repo, _), result) in targets.iter().zip(results) {
+        match result {
+            Some(Ok(Outcome::Updated(locked))) => {
+                remember_previous(&session, repo, &lock);
+                stale.extend(stale_files(&lock, &locked));
+                upsert_lock(&mut lock, *locked);
+                updated += 1;
+            }
+            Some(Ok(Outcome::UpToDate)) => skipped += 1,
+            Some(Ok(Outcome::Retagged(locked))) => {
+                upsert_lock(&mut lock, *locked);
+                skipped += 1;
+            }
+            Some(Ok(Outcome::Declined)) => declined += 1,
+            Some(Err(e))   => failures.push((repo.to_string(), e)),
+            None => {}
         }
     }
 
     let lock_str = toml::to_string_pretty(&lock).context("failed to serialize lockfile")?;
-    fs::write(lock_path, lock_str).context("failed to write plugins.lock")?;
+    transaction::write_atomic(lock_path, lock_str).context("failed to write plugins.lock")?;
+    session.transaction.commit();
+    remove_stale(config, &stale);
+
+    cancel::check()?;
+
+    if !failures.is_empty() {
+        print_failures("update", &failures);
+    }
 
     log::info!(
-        "\n{} {} updated, {} already up to date",
+        "\n{} {} updated, {} already up to date{}",
         "done!".green().bold(),
         updated.to_string().cyan(),
         skipped.to_string().dimmed(),
+        if declined > 0 { format!(", {} declined", declined.to_string().yellow()) } else { String::new() },
     );
 
+    if !failures.is_empty() {
+        bail!(Failure::partial("update", &failures, total));
+    }
+
     Ok(())
 }
 
-// true - Plugin was updated
-// false - plugin is up to date
 fn update_one(
+    session: &Session,
     repo: &str,
     entry: &PluginEntry,
-    cache_root: &Path,
-    lock: &mut LockFile,
-) -> Result<bool> {
-    let ref_ = match entry {
-        PluginEntry::Ref(r) => r.as_str(),
-        PluginEntry::Config(c) => c.ref_.as_str(),
-    };
-    let prebuilt_requested = match entry {
-        PluginEntry::Config(c) => c.prebuilt.unwrap_or(false),
-        _ => false,
-    };
+    existing: Option<&LockedPlugin>,
+    interactive: bool,
+) -> Result<Outcome> {
+    let source = entry.source(repo)?;
 
-    let cache_dir = cache_root.join(repo.replace('/', "__"));
-    let short_name = repo.split('/').last().unwrap_or(repo);
-    let artifact_dst = Path::new("plugins").join(format!("{}.so", short_name));
+    let Some(existing) = existing else {
+        bail!(Failure::Config("not installed, run 'eiipm install' first".into()));
+    };
 
-    if !cache_dir.exists() {
-        bail!("not in cache, run 'eiipm install' first");
+    if source.kind == SourceKind::Path {
+        return update_from_path(session, repo, entry, &source, existing);
     }
+    let cache_dir = session.cache_root.join(source.cache_key());
+    let _cache = session.caches.lock(&cache_dir);
+    let artifact_dst = session.artifact_path(&entry.name(repo)?);
 
-    let is_locked = lock.plugin.iter().any(|p| p.repo == repo);
-    if !is_locked {
-        bail!("not installed, run 'eiipm install' first");
+    if !cache_dir.exists() {
+        bail!(Failure::Config("not in cache, run 'eiipm install' first".into()));
     }
 
     let artifact_missing = !artifact_dst.exists();
 
-    //  Prebuilts
-    if prebuilt_requested {
-        let sp = spinner(&format!("{} {}", "fetching".cyan(), repo));
+    // compare against the lock rather than the cache, which may already be
+    // ahead if a previous build failed or was interrupted
+    let sha_before = existing.sha.clone();
+
+    let mut sp = spinner(&format!("{} {}", "fetching".cyan(), repo));
 
-        git::update_to_latest(&cache_dir, ref_, 1)
-            .with_context(|| format!("failed to fetch {}", repo))?;
+    // a version requirement moves to the highest tag that still matches it
+    let entry = &*resolve_version(repo, entry, &source, None)?;
 
-        let sha_after = head_sha(&cache_dir).unwrap_or_default();
-        let sha_before = lock.plugin.iter()
-            .find(|p| p.repo == repo)
-            .map(|p| p.sha.clone())
-            .unwrap_or_default();
+    git::update_to_latest(&source.url(), &cache_dir, &fetch_ref(entry), HISTORY_DEPTH)
+        .with_context(|| format!("failed to fetch {}", repo))?;
+
+    let sha_after = head_sha(&cache_dir).unwrap_or_default();
+
+    // drop leftovers of the last build that aren't ignored before rebuilding, so
+    // `artifacts` globs only see what this build produces; other plugins keep
+    // their build output
+    let rebuild = sha_before != sha_after || artifact_missing;
+    let has_artifacts = match entry.config().and_then(|c| c.artifacts.as_ref()) {
+        Some(specs) => !specs.is_empty(),
+        None => read_plugin_manifest(&cache_dir).and_then(|m| m.artifacts).is_some_and(|specs| !specs.is_empty()),
+    };
+    if rebuild && has_artifacts {
+        git::remove_untracked(&cache_dir).with_context(|| format!("failed to clean the checkout of {}", repo))?;
+    }
 
-        if sha_before == sha_after && !artifact_missing {
+    // tagged versions are shown as tags, anything else as commits
+    let (from, to) = match entry.version_req() {
+        Some(_) => (existing.ref_.clone(), entry.ref_().to_string()),
+        None => (sha_before[..8.min(sha_before.len())].to_string(), sha_after[..8.min(sha_after.len())].to_string()),
+    };
+
+    if sha_before == sha_after && !artifact_missing {
+        if entry.version_req().is_some()
+            && (existing.ref_ != entry.ref_() || existing.version.as_deref() != entry.version_req())
+        {
             sp.finish_with_message(format!(
-                "{} {} {}",
+                "{} {} {} {}",
                 "-".dimmed(),
                 repo.white(),
                 "already up to date".dimmed(),
+                format!("({} → {})", existing.ref_, entry.ref_()).dimmed(),
             ));
-            return Ok(false);
+            let mut locked = existing.clone();
+            locked.ref_ = entry.ref_().to_string();
+            locked.version = entry.version_req().map(str::to_string);
+            return Ok(Outcome::Retagged(Box::new(locked)));
         }
-
-        let plugin_manifest = read_plugin_manifest(&cache_dir);
-        let prebuilt_url = plugin_manifest
-            .as_ref()
-            .and_then(|m| m.prebuilt.as_ref())
-            .map(|p| p.url.clone())
-            .with_context(|| format!("{} requested prebuilt but plugin.toml has no [plugin.prebuilt] section", repo))?;
-
-        let resolved_url = resolve_prebuilt_url(&prebuilt_url, ref_);
-
-        sp.set_message(format!(
+        sp.finish_with_message(format!(
             "{} {} {}",
-            "downloading".cyan(),
-            repo,
-            if artifact_missing { "(restoring missing artifact)".yellow().to_string() } else { "".to_string() }
+            "-".dimmed(),
+            repo.white(),
+            "already up to date".dimmed(),
         ));
+        return Ok(Outcome::UpToDate);
+    }
 
-        download_prebuilt(&resolved_url, &artifact_dst)
-            .with_context(|| format!("failed to download prebuilt for {}", repo))?;
+    let mut changes = if sha_before != sha_after {
+        describe_changes(&cache_dir, &sha_before, &sha_after)
+    } else {
+        String::new()
+    };
+
+    if interactive && sha_before != sha_after {
+        sp.finish_and_clear();
+        log::info!(
+            "{} {} {} {}",
+            "update available".cyan().bold(),
+            repo.white().bold(),
+            from.dimmed(),
+            format!("→ {}", to).green(),
+        );
+        print_changes(&mem::take(&mut changes));
+
+        if !utils::confirm(&format!("update {}?", repo))? {
+            // leave the cache at the installed commit, like the lock
+            if let Err(e) = git::checkout(&cache_dir, &sha_before) {
+                log::warn!("{}: failed to restore the cached checkout: {}", repo, e);
+            }
+            log::info!("{} {} {}", "-".dimmed(), repo.white(), "update declined".dimmed());
+            return Ok(Outcome::Declined);
+        }
+        sp = spinner(&format!("{} {}", "updating".cyan(), repo));
+    }
+
+    //  Prebuilts
+    if let Some(checksum) = try_prebuilt(session, repo, entry, None, &cache_dir, &artifact_dst, &sp)? {
+        let files = install_files(session, repo, entry, &cache_dir, &artifact_dst)?;
 
         let finish_msg = if artifact_missing {
             format!("{} {} {}", "✔".green().bold(), repo.white().bold(), "artifact restored".yellow())
@@ -161,75 +274,30 @@ fn update_one(
                 "✔".green().bold(),
                 repo.white().bold(),
                 "(prebuilt)".dimmed(),
-                sha_before[..8.min(sha_before.len())].dimmed(),
-                format!("→ {}", &sha_after[..8.min(sha_after.len())]).green(),
+                from.dimmed(),
+                format!("→ {}", to).green(),
             )
         };
         sp.finish_with_message(finish_msg);
+        print_changes(&changes);
 
-        crate::functions::install::upsert_lock(lock, repo, ref_, &sha_after, &artifact_dst);
-        return Ok(true);
+        let mut locked = locked_plugin(session, repo, entry, &sha_after, &artifact_dst);
+        locked.method = InstallMethod::Prebuilt;
+        locked.sha256 = Some(checksum);
+        locked.files = files;
+        return Ok(Outcome::Updated(Box::new(locked)));
     }
 
     // Building method
-    let sha_before = head_sha(&cache_dir).unwrap_or_default();
-
-    let sp = spinner(&format!("{} {}", "fetching".cyan(), repo));
-
-    git::update_to_latest(&cache_dir, ref_, 1)
-        .with_context(|| format!("failed to fetch {}", repo))?;
-
-    let sha_after = head_sha(&cache_dir).unwrap_or_default();
-
-    if sha_before == sha_after && !artifact_missing {
-        sp.finish_with_message(format!(
-            "{} {} {}",
-            "-".dimmed(),
-            repo.white(),
-            "already up to date".dimmed(),
-        ));
-        return Ok(false);
-    }
-
     sp.set_message(format!(
         "{} {} {}",
-        "building".cyan(),
+        "fetched".green(),
         repo,
         if artifact_missing { "(restoring missing artifact)".yellow().to_string() } else { "".to_string() }
     ));
 
-    let plugin_manifest = read_plugin_manifest(&cache_dir);
-    let build_cmd = match entry {
-        PluginEntry::Config(c) => c.build.as_deref()
-            .or_else(|| plugin_manifest.as_ref().and_then(|m| m.build.as_deref()))
-            .unwrap_or(DEFAULT_BUILD),
-        _ => plugin_manifest.as_ref()
-            .and_then(|m| m.build.as_deref())
-            .unwrap_or(DEFAULT_BUILD),
-    };
-
-    crate::functions::install::run_build(build_cmd, &cache_dir)
-        .with_context(|| format!("build failed for {}", repo))?;
-
-    let artifact_rel = match entry {
-        PluginEntry::Config(c) => c.artifact.as_deref()
-            .or_else(|| plugin_manifest.as_ref().and_then(|m| m.artifact.as_deref()))
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("target/release/lib{}.so", short_name.replace('-', "_"))),
-        _ => plugin_manifest.as_ref()
-            .and_then(|m| m.artifact.as_deref())
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("target/release/lib{}.so", short_name.replace('-', "_"))),
-    };
-
-    let artifact_src = cache_dir.join(&artifact_rel);
-
-    if !artifact_src.exists() {
-        bail!("artifact not found at {} after build", artifact_src.display());
-    }
-
-    fs::copy(&artifact_src, &artifact_dst)
-        .with_context(|| format!("failed to copy artifact for {}", repo))?;
+    build_and_copy(session, repo, entry, &source, &cache_dir, &artifact_dst)?;
+    let files = install_files(session, repo, entry, &cache_dir, &artifact_dst)?;
 
     let finish_msg = if artifact_missing {
         format!("{} {} {}", "✔".green().bold(), repo.white().bold(), "artifact restored".yellow())
@@ -238,13 +306,98 @@ fn update_one(
             "{} {} {} {}",
             "✔".green().bold(),
             repo.white().bold(),
-            sha_before[..8.min(sha_before.len())].dimmed(),
-            format!("→ {}", &sha_after[..8.min(sha_after.len())]).green(),
+            from.dimmed(),
+            format!("→ {}", to).green(),
         )
     };
     sp.finish_with_message(finish_msg);
+    print_changes(&changes);
+
+    let mut locked = locked_plugin(session, repo, entry, &sha_after, &artifact_dst);
+    locked.files = files;
+    Ok(Outcome::Updated(Box::new(locked)))
+}
 
-    crate::functions::install::upsert_lock(lock, repo, ref_, &sha_after, &artifact_dst);
+/// Commits and changelog sections between the installed and the fetched revision,
+/// ready to print below the plugin's line.
+fn describe_changes(cache_dir: &Path, from: &str, to: &str) -> String {
+    let mut lines = vec![];
+
+    match git::log_between(cache_dir, from, to) {
+        Ok(commits) if !commits.is_empty() => {
+            lines.push(format!("  {}", "commits:".bold()));
+            for commit in commits.iter().take(MAX_COMMITS) {
+                lines.push(format!("    {} {}", commit.id[..8.min(commit.id.len())].yellow(), commit.summary));
+            }
+            if commits.len() > MAX_COMMITS {
+                lines.push(format!("    {}", format!("... and {} more", commits.len() - MAX_COMMITS).dimmed()));
+            }
+        }
+        Ok(_) => {}
+        // e.g. the installed commit is no longer in the cache
+        Err(e) => log::debug!("no commit log from {} to {}: {}", from, to, e),
+    }
+
+    let Some(name) = changelog::FILE_NAMES.iter().find(|name| cache_dir.join(name).is_file()) else {
+        return lines.join("\n");
+    };
+    // without the installed revision every section would look new
+    let Some(sections) = changelog::changed_since(cache_dir, from, name) else {
+        return lines.join("\n");
+    };
+    if !sections.is_empty() {
+        lines.push(format!("  {}", format!("{}:", name).bold()));
+        let text = sections.join("\n\n");
+        let total = text.lines().count();
+        for line in text.lines().take(MAX_CHANGELOG_LINES) {
+            lines.push(format!("    {}", line).trim_end().to_string());
+        }
+        if total > MAX_CHANGELOG_LINES {
+            lines.push(format!("    {}", format!("... and {} more lines in {}", total - MAX_CHANGELOG_LINES, name).dimmed()));
+        }
+    }
+
+    lines.join("\n")
+}
+
+fn print_changes(changes: &str) {
+    if !changes.is_empty() {
+        log::info!("{}", changes);
+    }
+}
+
+// Path sources are rebuilt when their HEAD, dirty state or any
+// source file changed since the last build.
+fn update_from_path(
+    session: &Session,
+    repo: &str,
+    entry: &PluginEntry,
+    source: &PluginSource,
+    locked: &LockedPlugin,
+) -> Result<Outcome> {
+    let src_dir = &session.config.join(&source.location);
+    // not while another entry with this path builds in it
+    let src_lock = session.caches.lock(&src_dir.canonicalize().unwrap_or_else(|_| src_dir.clone()));
+
+    let artifact_missing = !session.config.join(&locked.artifact).exists();
+    let (sha, dirty) = git::worktree_state(src_dir).unwrap_or_default();
+    let built_at = locked.built_at.parse::<u64>().ok();
+    let modified = utils::newest_mtime(src_dir)
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+
+    // built_at only has second precision, so compare whole seconds
+    let sources_changed = match (modified, built_at) {
+        (Some(modified), Some(built)) => modified > built,
+        _ => true,
+    };
+
+    if sha == locked.sha && dirty == locked.dirty && !sources_changed && !artifact_missing {
+        log::info!("{} {} {}", "-".dimmed(), repo.white(), "already up to date".dimmed());
+        return Ok(Outcome::UpToDate);
+    }
+    // install_from_path takes it again for the build
+    drop(src_lock);
 
-    Ok(true)
+    install_from_path(session, repo, entry, source).map(|locked| Outcome::Updated(Box::new(locked)))
 }
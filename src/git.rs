@@ -1,6 +1,8 @@
 //! Minimal fetch & checkout with git2
 
-use git2::{Error, FetchOptions, RemoteCallbacks, Repository};
+use crate::cancel;
+use git2::{AutotagOption, Direction, Error, ErrorCode, FetchOptions, Oid, Remote, RemoteCallbacks, Repository, Sort};
+use indexmap::IndexMap;
 use std::path::Path;
 
 /// Initialize a repo at `path` and fetch a specific commit from origin.
@@ -24,10 +26,14 @@ pub fn init_and_fetch(
     repo.remote("origin", repo_url)?;
 
     // prepare fetch options (shallow, depth=1)
-    let callbacks = RemoteCallbacks::new();
+    let callbacks = callbacks();
     let mut fetch_opts = FetchOptions::new();
     fetch_opts.remote_callbacks(callbacks);
-    fetch_opts.depth(fetch_depth);
+    // an auto-followed tag that already exists locally is left out of FETCH_HEAD
+    fetch_opts.download_tags(AutotagOption::None);
+    if !is_local(repo_url) {
+        fetch_opts.depth(fetch_depth);
+    }
 
     // Fetch the given commit
     {
@@ -49,15 +55,27 @@ pub fn init_and_fetch(
 
 /// Fetch the latest commit from `origin/<commit>`, checkout it,
 /// and discard all previous history (like a shallow reset).
-pub fn update_to_latest(repo_path: &Path, commit: &str, fetch_depth: i32) -> Result<(), Error> {
+///
+/// `origin` is repointed to `repo_url` first so a changed plugin source is honored.
+pub fn update_to_latest(
+    repo_url: &str,
+    repo_path: &Path,
+    commit: &str,
+    fetch_depth: i32,
+) -> Result<(), Error> {
     let repo = Repository::open(repo_path)?;
     set_temporary_identity(&repo)?;
+    repo.remote_set_url("origin", repo_url)?;
 
     // Prepare fetch options (shallow)
-    let callbacks = RemoteCallbacks::new();
+    let callbacks = callbacks();
     let mut fetch_opts = FetchOptions::new();
     fetch_opts.remote_callbacks(callbacks);
-    fetch_opts.depth(fetch_depth);
+    // an auto-followed tag that already exists locally is left out of FETCH_HEAD
+    fetch_opts.download_tags(AutotagOption::None);
+    if !is_local(repo_url) {
+        fetch_opts.depth(fetch_depth);
+    }
 
     // Fetch from origin
     {
@@ -82,6 +100,138 @@ pub fn update_to_latest(repo_path: &Path, commit: &str, fetch_depth: i32) -> Res
     Ok(())
 }
 
+/// Delete untracked files that aren't ignored from the checkout at `repo_path`.
+pub fn remove_untracked(repo_path: &Path) -> Result<(), Error> {
+    let repo = Repository::open(repo_path)?;
+    // a hard reset always checks out with plain FORCE, so this needs its own pass
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force().remove_untracked(true)))?;
+    Ok(())
+}
+
+/// Move the checkout at `repo_path` back to `commit`, which must already be in the repository.
+pub fn checkout(repo_path: &Path, commit: &str) -> Result<(), Error> {
+    let repo = Repository::open(repo_path)?;
+    let commit_obj = repo.find_commit(Oid::from_str(commit)?)?;
+
+    repo.reset(
+        commit_obj.as_object(),
+        git2::ResetType::Hard,
+        Some(git2::build::CheckoutBuilder::default().force()),
+    )?;
+
+    Ok(())
+}
+
+/// First line of a commit message.
+pub struct CommitSummary {
+    pub id: String,
+    pub summary: String,
+}
+
+/// Commits reachable from `to` but not from `from`, newest first.
+///
+/// Equivalent to:
+/// ```bash
+/// git log --oneline <from>..<to>
+/// ```
+pub fn log_between(repo_path: &Path, from: &str, to: &str) -> Result<Vec<CommitSummary>, Error> {
+    let repo = Repository::open(repo_path)?;
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    walk.push(Oid::from_str(to)?)?;
+    walk.hide(Oid::from_str(from)?)?;
+
+    let mut commits = vec![];
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        commits.push(CommitSummary {
+            id: commit.id().to_string(),
+            summary: commit.summary().unwrap_or_default().to_string(),
+        });
+    }
+
+    Ok(commits)
+}
+
+/// Contents of `file` as of `commit`, or None if it didn't exist then.
+/// Fails if `commit` itself isn't in the repository, e.g. because it is
+/// older than the fetched history.
+pub fn read_file_at(repo_path: &Path, commit: &str, file: &str) -> Result<Option<String>, Error> {
+    let repo = Repository::open(repo_path)?;
+    let tree = repo.find_commit(Oid::from_str(commit)?)?.tree()?;
+    let entry = match tree.get_path(Path::new(file)) {
+        Ok(entry) => entry,
+        Err(e) if e.code() == ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let blob = entry.to_object(&repo)?.peel_to_blob()?;
+    Ok(Some(String::from_utf8_lossy(blob.content()).to_string()))
+}
+
+/// Refs of the remote at `repo_url` and the commits they point to, without
+/// fetching anything. Annotated tags map to the commit they tag.
+///
+/// Equivalent to:
+/// ```bash
+/// git ls-remote <repo_url>
+/// ```
+pub fn ls_remote(repo_url: &str) -> Result<IndexMap<String, String>, Error> {
+    let mut remote = Remote::create_detached(repo_url)?;
+    let connection = remote.connect_auth(Direction::Fetch, Some(callbacks()), None)?;
+
+    let mut refs = IndexMap::new();
+    for head in connection.list()? {
+        let oid = head.oid().to_string();
+        match head.name().strip_suffix("^{}") {
+            // the peeled entry follows the tag object's own
+            Some(tag) => {
+                refs.insert(tag.to_string(), oid);
+            }
+            None => {
+                refs.entry(head.name().to_string()).or_insert(oid);
+            }
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Tag names of the remote at `repo_url`.
+pub fn remote_tags(repo_url: &str) -> Result<Vec<String>, Error> {
+    let refs = ls_remote(repo_url)?;
+    Ok(tags(&refs))
+}
+
+/// Tag names among `refs` as returned by [`ls_remote`].
+pub fn tags(refs: &IndexMap<String, String>) -> Vec<String> {
+    refs.keys().filter_map(|name| name.strip_prefix("refs/tags/")).map(str::to_string).collect()
+}
+
+/// HEAD commit of the repository containing `path` and whether its
+/// working tree has uncommitted (non-ignored) changes.
+pub fn worktree_state(path: &Path) -> Result<(String, bool), Error> {
+    let repo = Repository::discover(path)?;
+    let head = repo.head()?.peel_to_commit()?.id().to_string();
+
+    let mut opts = git2::StatusOptions::new();
+    opts.include_untracked(true).include_ignored(false);
+    let dirty = !repo.statuses(Some(&mut opts))?.is_empty();
+
+    Ok((head, dirty))
+}
+
+/// Callbacks that abort the transfer when Ctrl-C is pressed.
+fn callbacks<'a>() -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.transfer_progress(|_| !cancel::requested());
+    callbacks
+}
+
+/// libgit2's local transport does not support shallow fetches.
+fn is_local(repo_url: &str) -> bool {
+    repo_url.starts_with("file://")
+}
+
 /// Set a temporary identity in the repository configuration.
 fn set_temporary_identity(repo: &git2::Repository) -> Result<(), Error> {
     let mut config = repo.config()?;
@@ -0,0 +1,110 @@
+//! Previously installed versions of a plugin, kept for `eiipm rollback`.
+//!
+//! Every time `install` or `update` replaces an artifact, the old one is moved
+//! to `history/<plugin>/` under the cache together with its lock entry and a
+//! copy of its companion `files`. Only the most recent [`HISTORY_LIMIT`]
+//! versions are kept.
+
+use crate::schema::{HistoryEntry, HistoryFile, LockedPlugin};
+use crate::utils;
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const HISTORY_LIMIT: usize = 5;
+
+pub struct History {
+    dir: PathBuf,
+    /// Oldest first
+    pub entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    /// History of the plugin with the plugins.toml key `repo`. Plugins built
+    /// from the same repository each have their own.
+    pub fn open(cache_root: &Path, repo: &str) -> Result<Self> {
+        let dir = cache_root.join("history").join(utils::dir_name(repo));
+        let index = dir.join("history.toml");
+
+        let entries = if index.exists() {
+            let contents = fs::read_to_string(&index)
+                .with_context(|| format!("failed to read {}", index.display()))?;
+            let file: HistoryFile = toml::from_str(&contents)
+                .with_context(|| format!("failed to parse {}", index.display()))?;
+            file.entry
+        } else {
+            vec![]
+        };
+
+        Ok(History { dir, entries })
+    }
+
+    /// Keep a copy of `artifact` and of `files`, installed as described by `locked`.
+    ///
+    /// `files` pairs each of `locked.files` with where its content is now.
+    pub fn push(&mut self, locked: &LockedPlugin, artifact: &Path, files: &[(String, PathBuf)]) -> Result<()> {
+        let file = format!("{}-{}.so", locked.built_at, short(&locked.sha));
+
+        // a rebuild of a commit that is already kept replaces the older copy
+        let duplicate = |e: &HistoryEntry| {
+            e.file == file || (!locked.sha.is_empty() && e.plugin.sha == locked.sha && !e.plugin.dirty && !locked.dirty)
+        };
+        for old in self.entries.iter().filter(|e| duplicate(e)) {
+            self.remove_copies(old);
+        }
+        self.entries.retain(|e| !duplicate(e));
+
+        fs::create_dir_all(&self.dir).context("failed to create history dir")?;
+        fs::copy(artifact, self.dir.join(&file))
+            .with_context(|| format!("failed to copy {} to history", artifact.display()))?;
+
+        let entry = HistoryEntry { file, plugin: locked.clone() };
+        let files_dir = self.files_dir(&entry);
+        for (rel, current) in files {
+            let kept = files_dir.join(rel);
+            if let Some(parent) = kept.parent() {
+                fs::create_dir_all(parent).context("failed to create history dir")?;
+            }
+            fs::copy(current, &kept).with_context(|| format!("failed to copy {} to history", rel))?;
+        }
+        self.entries.push(entry);
+
+        while self.entries.len() > HISTORY_LIMIT {
+            let oldest = self.entries.remove(0);
+            self.remove_copies(&oldest);
+        }
+
+        self.save()
+    }
+
+    /// Remove the newest entry matching `sha` (a prefix is enough), or its newest one if `sha` is None.
+    pub fn take(&mut self, sha: Option<&str>) -> Option<HistoryEntry> {
+        let index = self.entries.iter().rposition(|e| sha.is_none_or(|sha| e.plugin.sha.starts_with(sha)))?;
+        Some(self.entries.remove(index))
+    }
+
+    pub fn path(&self, entry: &HistoryEntry) -> PathBuf {
+        self.dir.join(&entry.file)
+    }
+
+    /// Where the companion files of `entry` are kept, by their path in the config directory.
+    pub fn files_dir(&self, entry: &HistoryEntry) -> PathBuf {
+        self.dir.join(entry.file.trim_end_matches(".so"))
+    }
+
+    /// Delete the copies kept for `entry`.
+    pub fn remove_copies(&self, entry: &HistoryEntry) {
+        let _ = fs::remove_file(self.path(entry));
+        let _ = fs::remove_dir_all(self.files_dir(entry));
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let file = HistoryFile { entry: self.entries.clone() };
+        let contents = toml::to_string_pretty(&file).context("failed to serialize history")?;
+        fs::write(self.dir.join("history.toml"), contents).context("failed to write history")
+    }
+}
+
+pub fn short(sha: &str) -> &str {
+    &sha[..8.min(sha.len())]
+}
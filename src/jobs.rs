@@ -0,0 +1,106 @@
+//! Small helpers for running plugin work concurrently.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// Default number of parallel jobs, one per available CPU.
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Run `f` over `items` on at most `jobs` threads.
+/// Results are returned in the same order as `items`.
+pub fn run_parallel<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+
+    thread::scope(|s| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(item) = items.get(i) else { break };
+                let result = f(item);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every item is processed"))
+        .collect()
+}
+
+/// Counting semaphore used to bound how many builds run at once.
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    available: Condvar,
+}
+
+pub struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits.max(1)),
+            available: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.available.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.permits.lock().unwrap() += 1;
+        self.0.available.notify_one();
+    }
+}
+
+/// Lets one job at a time work inside a directory, e.g. a cache dir shared by
+/// several plugins that come from the same repository.
+#[derive(Default)]
+pub struct DirLocks {
+    held: Mutex<HashSet<PathBuf>>,
+    released: Condvar,
+}
+
+pub struct DirGuard<'a> {
+    locks: &'a DirLocks,
+    dir: PathBuf,
+}
+
+impl DirLocks {
+    pub fn lock(&self, dir: &Path) -> DirGuard<'_> {
+        let mut held = self.held.lock().unwrap();
+        while held.contains(dir) {
+            held = self.released.wait(held).unwrap();
+        }
+        held.insert(dir.to_path_buf());
+        DirGuard { locks: self, dir: dir.to_path_buf() }
+    }
+}
+
+impl Drop for DirGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.held.lock().unwrap().remove(&self.dir);
+        self.locks.released.notify_all();
+    }
+}
@@ -1,11 +1,27 @@
 mod utils;
+mod archive;
+mod artifacts;
+mod changelog;
+mod versions;
+mod cancel;
+mod build;
 mod opts;
 mod git;
+mod history;
+mod error;
+mod editor;
+mod jobs;
+mod prebuilt;
+mod progress;
 mod schema;
+mod source;
+mod transaction;
+mod paths;
 mod functions;
 
 use clap::Parser;
 use opts::{Args, Commands};
+use paths::ConfigDir;
 use functions::{
     install::install_plugins,
     init::init_plugin_repo,
@@ -15,8 +31,13 @@ use functions::{
     clean::clean_plugins,
     list::list_plugins,
     update::update_plugins,
+    outdated::outdated_plugins,
+    logs::show_logs,
+    rollback::rollback_plugin,
 };
 use log::Level;
+use colored::Colorize;
+use std::process;
 
 fn main() {
     let args = Args::parse();
@@ -26,52 +47,61 @@ fn main() {
         log::info!("Debug logging enabled");
     }
 
-    match args.command {
-        Commands::Init => {
-            if let Err(e) = init_plugin_repo() {
-                log::error!("Failed to initialize plugin repository: {}", e);
-            }
-        }
-        Commands::Install => {
-            if let Err(e) = install_plugins() {
-                log::error!("Failed to install plugins: {}", e);
-            }
-        }
-        Commands::Add(add_args) => {
-            if let Err(e) = add_plugin(add_args) {
-                log::error!("Failed to add plugin: {}", e);
-            }
-        }
-        Commands::Remove { plugin } => {
-            if let Err(e) = remove_plugin(plugin) {
-                log::error!("Failed to remove plugin: {}", e);
-            }
-        }
-        Commands::Update { plugin: maybe_plugin } => {
-            if let Err(e) = update_plugins(maybe_plugin) {
-                log::error!("Failed to update plugins: {}", e);
-            }
-        }
-        Commands::Clean => {
-            if let Err(e) = clean_plugins() {
-                log::error!("Failed to clean plguins: {}", e);
-            }
-        }
-        Commands::CacheClean => {
-            if let Err(e) = clean_cache() {
-                log::error!("Failed to clean cache: {}", e);
-            }
+    utils::set_assume(assume_from(args.yes, args.no));
+    // other commands keep the default Ctrl-C, which exits at once
+    if matches!(args.command, Commands::Install(_) | Commands::Update { .. } | Commands::Outdated(_)) {
+        cancel::install_handler();
+    }
+
+    let config = match args.command {
+        Commands::Init => ConfigDir::for_init(args.config_dir),
+        _ => ConfigDir::discover(args.config_dir),
+    };
+    log::debug!("using config directory {}", config.root().display());
+
+    let (result, what) = match args.command {
+        Commands::Init => (init_plugin_repo(&config), "Failed to initialize plugin repository"),
+        Commands::Install(install_args) => (install_plugins(&config, install_args), "Failed to install plugins"),
+        Commands::Add(add_args) => (add_plugin(&config, add_args), "Failed to add plugin"),
+        Commands::Remove { plugin } => (remove_plugin(&config, plugin), "Failed to remove plugin"),
+        Commands::Rollback { plugin, to } => (rollback_plugin(&config, plugin, to), "Failed to roll back plugin"),
+        Commands::Update { plugin: maybe_plugin, interactive, jobs } => {
+            (update_plugins(&config, maybe_plugin, interactive, jobs), "Failed to update plugins")
         }
-        Commands::List => {
-            if let Err(e) = list_plugins() {
-                log::error!("Failed to list plugins: {}", e);
-            }
+        Commands::Outdated(outdated_args) => (outdated_plugins(&config, outdated_args), "Failed to check for updates"),
+        Commands::Clean => (clean_plugins(&config), "Failed to clean plugins"),
+        Commands::CacheClean => (clean_cache(), "Failed to clean cache"),
+        Commands::List => (list_plugins(&config), "Failed to list plugins"),
+        Commands::Logs { plugin } => (show_logs(&config, plugin), "Failed to show build log"),
+    };
+
+    if let Err(e) = result {
+        if error::is_aborted(&e) {
+            log::info!("{}", "aborted".dimmed());
+        } else {
+            log::error!("{}: {}", what, e);
         }
+        process::exit(error::exit_code(&e));
+    }
+}
+
+fn assume_from(yes: bool, no: bool) -> utils::Assume {
+    let env_yes = std::env::var("EIIPM_ASSUME_YES")
+        .map(|v| !matches!(v.trim().to_lowercase().as_str(), "" | "0" | "false" | "no"))
+        .unwrap_or(false);
+
+    if no {
+        utils::Assume::No
+    } else if yes || env_yes {
+        utils::Assume::Yes
+    } else {
+        utils::Assume::Ask
     }
 }
 
 fn set_debug_levels(debug_mode: bool) {
     let mut builder = env_logger::Builder::from_default_env();
+    builder.target(env_logger::Target::Pipe(Box::new(progress::LogWriter)));
 
     if debug_mode {
         builder
@@ -1,4 +1,6 @@
-use clap::{Parser, Subcommand};
+use clap::{Args as ClapArgs, Parser, Subcommand};
+use std::path::PathBuf;
+use crate::jobs::default_jobs;
 
 /// Simple plugin manager for Ewwii.
 #[derive(Parser, Debug)]
@@ -8,6 +10,18 @@ pub struct Args {
     #[arg(long, global = true)]
     pub debug: bool,
 
+    /// Answer yes to every confirmation prompt (or set EIIPM_ASSUME_YES=1)
+    #[arg(short, long, global = true, conflicts_with = "no")]
+    pub yes: bool,
+
+    /// Answer no to every confirmation prompt
+    #[arg(long, global = true)]
+    pub no: bool,
+
+    /// Ewwii config directory to operate on (or set EWWII_CONFIG_DIR)
+    #[arg(long, global = true, value_name = "DIR")]
+    pub config_dir: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -18,7 +32,7 @@ pub enum Commands {
     Init,
 
     /// Install everything in plugins.toml
-    Install,
+    Install(InstallArgs),
 
     /// Add a plugin to plugins.toml
     Add(AddArgs),
@@ -29,12 +43,32 @@ pub enum Commands {
         plugin: String,
     },
 
+    /// Restore a previously installed version of a plugin without rebuilding
+    Rollback {
+        /// Plugin to roll back
+        plugin: String,
+
+        /// Commit to go back to [default: the version installed before the current one]
+        #[arg(long, value_name = "SHA")]
+        to: Option<String>,
+    },
+
     /// Update all plugins
     Update {
         /// Only update a singular plugin
-        plugin: Option<String>
+        plugin: Option<String>,
+
+        /// Show what changed in each plugin and ask before applying its update
+        #[arg(short, long)]
+        interactive: bool,
+
+        #[command(flatten)]
+        jobs: JobsArgs,
     },
 
+    /// Show plugins with newer commits or versions, without updating them
+    Outdated(OutdatedArgs),
+
     /// Clean entries in plugins/ that are not present in plugins.toml
     Clean,
 
@@ -43,13 +77,74 @@ pub enum Commands {
 
     /// List all plugins
     List,
+
+    /// Show the most recent build log of a plugin
+    Logs {
+        /// Plugin to show the build log of
+        plugin: String,
+    },
+}
+
+#[derive(Parser, Debug)]
+pub struct InstallArgs {
+    /// Install the exact commits in plugins.lock and never modify it
+    #[arg(long, visible_alias = "frozen")]
+    pub locked: bool,
+
+    /// Stop at the first failing plugin instead of installing the rest
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    #[command(flatten)]
+    pub jobs: JobsArgs,
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct JobsArgs {
+    /// Number of plugins to fetch and build in parallel [default: number of CPUs]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+    /// Maximum number of builds running at once [default: same as --jobs]
+    #[arg(long)]
+    pub build_jobs: Option<usize>,
+    /// Stop builds that take longer than this many seconds, unless the plugin sets its own `timeout`
+    #[arg(long, value_name = "SECS")]
+    pub build_timeout: Option<u64>,
+}
+
+impl JobsArgs {
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(default_jobs).max(1)
+    }
+
+    pub fn build_jobs(&self) -> usize {
+        self.build_jobs.unwrap_or_else(|| self.jobs()).max(1)
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct OutdatedArgs {
+    /// Only check a singular plugin
+    pub plugin: Option<String>,
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+    /// Number of remotes to query in parallel [default: number of CPUs]
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+}
+
+impl OutdatedArgs {
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(default_jobs).max(1)
+    }
 }
 
 #[derive(Parser, Debug)]
 pub struct AddArgs {
-    /// Plugin to add. Format: "user/repo"
+    /// Plugin to add. Format: "user/repo", "gitlab:user/repo", a git URL or "file://<path>"
     pub plugin: String,
-    /// Branch/tag/sha to reference
+    /// Branch/tag/sha to reference, or a version requirement like "^1.2"
     #[arg(long = "ref")]
     pub ref_: Option<String>,
     /// Prefer prebuilt binary over building from source
@@ -61,4 +156,7 @@ pub struct AddArgs {
     /// Override artifact path
     #[arg(long)]
     pub artifact: Option<String>,
+    /// Install as plugins/<NAME>.so instead of using the repository name
+    #[arg(long)]
+    pub name: Option<String>,
 }
@@ -0,0 +1,91 @@
+//! The ewwii config directory eiipm operates on.
+//!
+//! Resolved in this order:
+//! 1. `--config-dir <DIR>`
+//! 2. `EWWII_CONFIG_DIR`
+//! 3. the nearest parent of the current directory containing plugins.toml
+//! 4. `$XDG_CONFIG_HOME/ewwii`
+//!
+//! `init` skips the last two steps and uses the current directory instead.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_DIR_ENV: &str = "EWWII_CONFIG_DIR";
+
+#[derive(Clone, Debug)]
+pub struct ConfigDir {
+    root: PathBuf,
+}
+
+impl ConfigDir {
+    pub fn new(root: PathBuf) -> Self {
+        ConfigDir { root }
+    }
+
+    /// Find the config directory for commands that need an existing plugins.toml.
+    pub fn discover(flag: Option<PathBuf>) -> Self {
+        if let Some(dir) = explicit(flag) {
+            return ConfigDir::new(dir);
+        }
+
+        let cwd = env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        if let Some(dir) = cwd.ancestors().find(|d| d.join("plugins.toml").is_file()) {
+            return ConfigDir::new(dir.to_path_buf());
+        }
+
+        match dirs::config_dir() {
+            Some(config) => ConfigDir::new(config.join("ewwii")),
+            None => ConfigDir::new(cwd),
+        }
+    }
+
+    /// Directory `init` should create a new config in.
+    pub fn for_init(flag: Option<PathBuf>) -> Self {
+        match explicit(flag) {
+            Some(dir) => ConfigDir::new(dir),
+            None => ConfigDir::new(PathBuf::from(".")),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn toml(&self) -> PathBuf {
+        self.root.join("plugins.toml")
+    }
+
+    pub fn lock(&self) -> PathBuf {
+        self.root.join("plugins.lock")
+    }
+
+    pub fn plugins(&self) -> PathBuf {
+        self.root.join("plugins")
+    }
+
+    /// Resolve a path stored relative to the config directory (lockfile artifacts, `path` sources).
+    pub fn join(&self, rel: impl AsRef<Path>) -> PathBuf {
+        self.root.join(rel)
+    }
+
+    /// Inverse of [`ConfigDir::join`], used before writing paths into the lockfile.
+    pub fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root).unwrap_or(path).to_path_buf()
+    }
+
+    /// Remove the directories above a deleted `path` that are left empty, up to the config directory or `plugins/`.
+    pub fn remove_empty_parents(&self, path: &Path) {
+        let plugins = self.plugins();
+        for dir in path.ancestors().skip(1) {
+            if dir == self.root || dir == plugins || !dir.starts_with(&self.root) || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+fn explicit(flag: Option<PathBuf>) -> Option<PathBuf> {
+    flag.or_else(|| env::var_os(CONFIG_DIR_ENV).filter(|v| !v.is_empty()).map(PathBuf::from))
+}
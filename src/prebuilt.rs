@@ -0,0 +1,419 @@
+//! Downloading and verifying prebuilt plugin binaries.
+//!
+//! The download URL is either one template or a table with one URL per target.
+//! Per-target tables are keyed by Rust target triple (`x86_64-unknown-linux-gnu`),
+//! `<arch>-<os>-<libc>` or `<arch>-<os>`, and the most specific key matching
+//! this machine wins.
+//!
+//! `[plugin.prebuilt]` can pin the expected SHA-256 of the download, either
+//! directly (one hash, or one per target) or through a
+//! `checksums_url` pointing at a SHA256SUMS style file. The download is
+//! verified while it is still staged, so a mismatching binary never reaches
+//! `plugins/`.
+//!
+//! Release assets can also be `.tar.gz` or `.zip` archives. Checksums and
+//! signatures apply to the archive as downloaded, then the plugin binary is
+//! extracted from it (see [`crate::archive`]).
+//!
+//! When plugins.toml pins a `pubkey` for the plugin, the download must also
+//! carry a detached minisign signature made by one of those keys. Signatures
+//! are checked offline against the pinned keys only; keys published next to
+//! the binary are never trusted.
+
+use crate::archive;
+use crate::error::Failure;
+use crate::schema::{ArchiveFormat, PrebuiltConfig, PublicKeys, TargetSpec};
+use crate::transaction::Transaction;
+use anyhow::{bail, Context, Result};
+use minisign_verify::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Read};
+use std::env::consts::{ARCH, OS};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Values substituted into prebuilt URLs and archive members, besides the
+/// ones describing this machine.
+pub struct Placeholders<'a> {
+    /// `{version}`, the ref from plugins.toml
+    pub version: &'a str,
+    /// `{sha}`, the commit the plugin was fetched at
+    pub sha: &'a str,
+    /// `{name}`, the plugin's short name
+    pub name: &'a str,
+}
+
+impl Placeholders<'_> {
+    pub fn apply(&self, template: &str) -> String {
+        template
+            .replace("{version}", self.version)
+            .replace("{sha}", self.sha)
+            .replace("{name}", self.name)
+            .replace("{target}", target_triple())
+            .replace("{arch}", ARCH)
+            .replace("{os}", OS)
+            .replace("{libc}", libc().unwrap_or(""))
+    }
+}
+
+/// Download URL of the prebuilt for this machine.
+pub fn resolve_url(config: &PrebuiltConfig, vars: &Placeholders) -> Result<String> {
+    Ok(vars.apply(for_target(&config.url, "url")?))
+}
+
+/// Rust target triple of this machine, e.g. `x86_64-unknown-linux-musl`.
+pub fn target_triple() -> &'static str {
+    static TRIPLE: OnceLock<String> = OnceLock::new();
+    TRIPLE.get_or_init(|| match OS {
+        "linux" => format!("{}-unknown-linux-{}", ARCH, libc().unwrap_or("gnu")),
+        "macos" => format!("{}-apple-darwin", ARCH),
+        "windows" => format!("{}-pc-windows-msvc", ARCH),
+        os => format!("{}-unknown-{}", ARCH, os),
+    })
+}
+
+/// C library of this machine, `gnu` or `musl` on Linux and None elsewhere.
+pub fn libc() -> Option<&'static str> {
+    static LIBC: OnceLock<Option<&str>> = OnceLock::new();
+    *LIBC.get_or_init(|| {
+        if OS != "linux" {
+            return None;
+        }
+        // a glibc build of eiipm can only be running on glibc
+        if cfg!(target_env = "gnu") {
+            return Some("gnu");
+        }
+
+        let has_loader = |prefix: &str| {
+            ["/lib", "/lib64", "/usr/lib"].iter().any(|dir| {
+                fs::read_dir(dir).is_ok_and(|entries| {
+                    entries.flatten().any(|e| e.file_name().to_string_lossy().starts_with(prefix))
+                })
+            })
+        };
+        // glibc systems can have musl installed next to it, but not the other way around
+        if has_loader("ld-musl-") && !has_loader("ld-linux") { Some("musl") } else { Some("gnu") }
+    })
+}
+
+/// Keys of this machine in per-target tables, most specific first,
+/// e.g. `x86_64-unknown-linux-gnu`, `x86_64-linux-gnu`, `x86_64-linux`.
+pub fn target_keys() -> Vec<String> {
+    let mut keys = vec![target_triple().to_string()];
+    if let Some(libc) = libc() {
+        keys.push(format!("{}-{}-{}", ARCH, OS, libc));
+    }
+    keys.push(format!("{}-{}", ARCH, OS));
+    keys
+}
+
+/// The value of `spec` for this machine. `key` names the plugin.toml key in errors.
+fn for_target<'a>(spec: &'a TargetSpec, key: &str) -> Result<&'a str> {
+    match spec {
+        TargetSpec::Single(value) => Ok(value),
+        TargetSpec::PerTarget(values) => match target_keys().iter().find_map(|k| values.get(k)) {
+            Some(value) => Ok(value),
+            None => bail!(Failure::Config(format!(
+                "plugin.toml has no prebuilt {} for {} (has: {})",
+                key,
+                target_triple(),
+                values.keys().cloned().collect::<Vec<_>>().join(", ")
+            ))),
+        },
+    }
+}
+
+/// Download `url` into `dst` through `tx` and return its SHA-256 (of the archive, for archives).
+///
+/// The download must match the checksum from plugin.toml, and `locked` (the
+/// checksum in plugins.lock) when installing with `--locked`. If `pubkeys` is
+/// set it must also be signed by one of them.
+pub fn install(
+    tx: &Transaction,
+    dst: &Path,
+    config: &PrebuiltConfig,
+    url: &str,
+    vars: &Placeholders,
+    locked: Option<&str>,
+    pubkeys: Option<&PublicKeys>,
+) -> Result<String> {
+    let expected = expected_sha256(config, url, vars)?;
+    let signature = match pubkeys {
+        Some(pubkeys) => {
+            let keys = parse_keys(pubkeys)?;
+            let signature_url = match &config.signature_url {
+                Some(signature_url) => vars.apply(signature_url),
+                None => format!("{}.minisig", url),
+            };
+            Some((keys, fetch_signature(&signature_url, url)?))
+        }
+        None => None,
+    };
+    let format = config.archive.or_else(|| ArchiveFormat::from_url(url));
+    let member = config.member.as_deref().map(|m| vars.apply(m));
+
+    let mut checksum = String::new();
+    tx.install(dst, |staged| {
+        download(url, staged)?;
+        checksum = sha256_file(staged)?;
+
+        if let Some(expected) = &expected {
+            verify(url, &checksum, expected, "plugin.toml")?;
+        }
+        if let Some(locked) = locked {
+            verify(url, &checksum, locked, "plugins.lock")?;
+        }
+        if let Some((keys, signature)) = &signature {
+            verify_signature(url, staged, keys, signature)?;
+        }
+
+        if let Some(format) = format {
+            let downloaded = staged.with_extension("archive");
+            fs::rename(staged, &downloaded).context("failed to stage archive")?;
+            let result = archive::extract(format, &downloaded, member.as_deref(), staged);
+            let _ = fs::remove_file(&downloaded);
+            let name = result.with_context(|| format!("failed to extract plugin from {}", url))?;
+            log::debug!("extracted {} from {}", name, url);
+        }
+        Ok(())
+    })?;
+
+    Ok(checksum)
+}
+
+pub fn download(url: &str, dst: &Path) -> Result<()> {
+    let response = ureq::get(url)
+        .call()
+        .with_context(|| format!("HTTP request failed for {}", url))?;
+
+    if response.status() != 200 {
+        bail!(Failure::Fetch(format!("server returned HTTP {} for {}", response.status(), url)));
+    }
+
+    let mut out = fs::File::create(dst)
+        .with_context(|| format!("failed to create {}", dst.display()))?;
+
+    io::copy(&mut response.into_body().as_reader(), &mut out)
+        .context("failed to write downloaded binary")?;
+
+    Ok(())
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).with_context(|| format!("failed to hash {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// The checksum plugin.toml expects for `url`, if it declares one.
+fn expected_sha256(config: &PrebuiltConfig, url: &str, vars: &Placeholders) -> Result<Option<String>> {
+    if let Some(spec) = &config.sha256 {
+        return Ok(Some(for_target(spec, "sha256")?.trim().to_lowercase()));
+    }
+
+    let Some(checksums_url) = &config.checksums_url else {
+        return Ok(None);
+    };
+    let checksums_url = vars.apply(checksums_url);
+
+    let body = ureq::get(&checksums_url)
+        .call()
+        .with_context(|| format!("HTTP request failed for {}", checksums_url))?
+        .into_body()
+        .read_to_string()
+        .with_context(|| format!("failed to read {}", checksums_url))?;
+
+    let file_name = url.split(['?', '#']).next().unwrap_or(url).rsplit('/').next().unwrap_or(url);
+    match find_checksum(&body, file_name) {
+        Some(hash) => Ok(Some(hash.to_lowercase())),
+        None => bail!(Failure::Verify(format!("{} has no checksum for {}", checksums_url, file_name))),
+    }
+}
+
+/// Look up `file_name` in a `sha256sum` style listing (`<hash>  <name>` or `<hash> *<name>`).
+/// A file holding nothing but a hash applies to any name.
+fn find_checksum<'a>(listing: &'a str, file_name: &str) -> Option<&'a str> {
+    let lines: Vec<&str> = listing.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+
+    if let [only] = lines.as_slice()
+        && !only.contains(char::is_whitespace)
+    {
+        return Some(only);
+    }
+
+    lines.iter().find_map(|line| {
+        let (hash, name) = line.split_once(char::is_whitespace)?;
+        let name = name.trim_start().trim_start_matches('*');
+        (name == file_name || name.rsplit('/').next() == Some(file_name)).then_some(hash)
+    })
+}
+
+fn verify(url: &str, actual: &str, expected: &str, source: &str) -> Result<()> {
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        bail!(Failure::Verify(format!(
+            "checksum mismatch for {}: {} expects sha256 {}, got {}",
+            url, source, expected.trim(), actual
+        )));
+    }
+    Ok(())
+}
+
+/// Parse the pinned keys, each either the base64 key line or a whole `minisign.pub`.
+fn parse_keys(pubkeys: &PublicKeys) -> Result<Vec<PublicKey>> {
+    if pubkeys.keys().is_empty() {
+        bail!(Failure::Config("pubkey is set but lists no keys".into()));
+    }
+
+    let keys = pubkeys
+        .keys()
+        .iter()
+        .map(|key| {
+            let key = key.trim();
+            let parsed = if key.contains('\n') { PublicKey::decode(key) } else { PublicKey::from_base64(key) };
+            parsed.map_err(|e| Failure::Config(format!("invalid minisign public key {}: {}", key, e)))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(keys)
+}
+
+fn fetch_signature(signature_url: &str, url: &str) -> Result<Signature> {
+    let body = match ureq::get(signature_url).call() {
+        Ok(response) => response
+            .into_body()
+            .read_to_string()
+            .with_context(|| format!("failed to read {}", signature_url))?,
+        Err(ureq::Error::StatusCode(404)) => bail!(Failure::Verify(format!(
+            "refusing unsigned prebuilt {}: no signature at {}",
+            url, signature_url
+        ))),
+        Err(e) => return Err(e).with_context(|| format!("HTTP request failed for {}", signature_url)),
+    };
+
+    let signature = Signature::decode(&body)
+        .map_err(|e| Failure::Verify(format!("invalid signature at {}: {}", signature_url, e)))?;
+    Ok(signature)
+}
+
+fn verify_signature(url: &str, path: &Path, keys: &[PublicKey], signature: &Signature) -> Result<()> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+
+    if !keys.iter().any(|key| key.verify(&bytes, signature, false).is_ok()) {
+        bail!(Failure::Verify(format!("signature check failed for {}: not signed by a trusted key", url)));
+    }
+    log::debug!("{} is signed by a trusted key", url);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_A: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+    const HASH_B: &str = "60303ae22b998861bce3b28f33eec1be758a213c86c93c076dbe9f558c11c752";
+
+    #[test]
+    fn checksum_listing_formats() {
+        let text = format!("{}  libfoo-x86_64-linux.so\n{} *libfoo-aarch64-linux.so\n", HASH_A, HASH_B);
+        assert_eq!(find_checksum(&text, "libfoo-x86_64-linux.so"), Some(HASH_A));
+        assert_eq!(find_checksum(&text, "libfoo-aarch64-linux.so"), Some(HASH_B));
+
+        // paths in the listing match on their file name
+        let text = format!("{}  dist/libfoo.so\n", HASH_A);
+        assert_eq!(find_checksum(&text, "libfoo.so"), Some(HASH_A));
+    }
+
+    #[test]
+    fn checksum_not_listed() {
+        let text = format!("{}  libfoo-x86_64-linux.so\n{}  libfoo.so.minisig\n", HASH_A, HASH_B);
+        assert_eq!(find_checksum(&text, "libfoo-aarch64-linux.so"), None);
+        assert_eq!(find_checksum(&text, "libfoo.so"), None);
+        assert_eq!(find_checksum("", "libfoo.so"), None);
+    }
+
+    #[test]
+    fn checksum_file_with_only_a_hash() {
+        assert_eq!(find_checksum(&format!("{}\n", HASH_A), "anything.so"), Some(HASH_A));
+        assert_eq!(find_checksum(&format!("\n  {}  \n\n", HASH_A), "anything.so"), Some(HASH_A));
+        // a single listing line still has to name the file
+        assert_eq!(find_checksum(&format!("{}  other.so\n", HASH_A), "anything.so"), None);
+    }
+
+    #[test]
+    fn checksum_comparison() {
+        assert!(verify("url", HASH_A, &format!("{}\n", HASH_A.to_uppercase()), "plugin.toml").is_ok());
+
+        let err = verify("url", HASH_A, HASH_B, "plugin.toml").unwrap_err();
+        assert!(crate::error::is_verify(&err));
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+    }
+
+    fn per_target(entries: &[(&str, &str)]) -> TargetSpec {
+        TargetSpec::PerTarget(entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect())
+    }
+
+    #[test]
+    fn target_keys_most_specific_first() {
+        let keys = target_keys();
+        assert_eq!(keys.first().map(String::as_str), Some(target_triple()));
+        assert_eq!(keys.last(), Some(&format!("{}-{}", ARCH, OS)));
+        assert!(target_triple().starts_with(&format!("{}-", ARCH)));
+
+        match libc() {
+            Some(libc) => {
+                let expected = [target_triple().to_string(), format!("{}-{}-{}", ARCH, OS, libc), format!("{}-{}", ARCH, OS)];
+                assert_eq!(keys, expected);
+                assert!(target_triple().ends_with(libc));
+            }
+            None => assert_eq!(keys.len(), 2),
+        }
+    }
+
+    #[test]
+    fn for_target_fallback_order() {
+        let triple = target_triple();
+        let arch_os = format!("{}-{}", ARCH, OS);
+        let with_libc = format!("{}-{}", arch_os, libc().unwrap_or("none"));
+
+        assert_eq!(for_target(&TargetSpec::Single("one".into()), "url").unwrap(), "one");
+
+        // the order of the table doesn't matter, the most specific key wins
+        let spec = per_target(&[(&arch_os, "arch-os"), (&with_libc, "libc"), (triple, "triple")]);
+        assert_eq!(for_target(&spec, "url").unwrap(), "triple");
+
+        let spec = per_target(&[(&arch_os, "arch-os"), (&with_libc, "libc")]);
+        let expected = if libc().is_some() { "libc" } else { "arch-os" };
+        assert_eq!(for_target(&spec, "url").unwrap(), expected);
+
+        let spec = per_target(&[("other-arch-linux", "other"), (&arch_os, "arch-os")]);
+        assert_eq!(for_target(&spec, "url").unwrap(), "arch-os");
+    }
+
+    #[test]
+    fn for_target_missing() {
+        let spec = per_target(&[("riscv32-none", "a"), ("sparc-solaris", "b")]);
+        let err = for_target(&spec, "sha256").unwrap_err().to_string();
+        assert!(err.contains(&format!("no prebuilt sha256 for {}", target_triple())), "{}", err);
+        assert!(err.contains("(has: riscv32-none, sparc-solaris)"), "{}", err);
+    }
+
+    #[test]
+    fn placeholders() {
+        let vars = Placeholders { version: "v1.2.0", sha: "0123abc", name: "clock" };
+        assert_eq!(
+            vars.apply("https://example.com/{version}/{name}-{arch}-{os}.so?c={sha}"),
+            format!("https://example.com/v1.2.0/clock-{}-{}.so?c=0123abc", ARCH, OS)
+        );
+        assert_eq!(vars.apply("{name}-{target}.so"), format!("clock-{}.so", target_triple()));
+    }
+}
@@ -0,0 +1,41 @@
+//! Shared progress display.
+//!
+//! All spinners are attached to one `MultiProgress` so that plugins processed
+//! in parallel each get their own line, and log output is routed through it
+//! so it doesn't tear the spinners.
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub fn multi() -> &'static MultiProgress {
+    static MULTI: OnceLock<MultiProgress> = OnceLock::new();
+    MULTI.get_or_init(MultiProgress::new)
+}
+
+pub fn spinner(msg: &str) -> ProgressBar {
+    let sp = multi().add(ProgressBar::new_spinner());
+    sp.set_style(
+        ProgressStyle::default_spinner()
+            .tick_strings(&["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"])
+            .template("{spinner:.cyan} {msg}")
+            .unwrap(),
+    );
+    sp.enable_steady_tick(Duration::from_millis(80));
+    sp.set_message(msg.to_string());
+    sp
+}
+
+/// Log target that writes to stderr without clobbering active spinners.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        multi().suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
@@ -1,5 +1,14 @@
 use serde::{Serialize, Deserialize};
 use indexmap::IndexMap;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::path::Path;
+use crate::error::Failure;
+use crate::source::{PluginSource, SourceKind};
+use crate::versions;
+
+/// Ref used when a plugin entry doesn't specify one
+pub const DEFAULT_REF: &str = "main";
 
 // plugins.toml schema
 
@@ -8,20 +17,231 @@ pub struct PluginsFile {
     pub plugins: IndexMap<String, PluginEntry>
 }
 
-#[derive(Deserialize, Serialize)]
+impl PluginsFile {
+    /// Fail on entries that can't be installed as written: two plugins
+    /// installed as the same file, or a ref and a version requirement together.
+    pub fn check(&self) -> Result<()> {
+        let mut names: IndexMap<String, Vec<&str>> = IndexMap::new();
+        for (key, entry) in &self.plugins {
+            names.entry(entry.name(key)?).or_default().push(key);
+
+            if entry.config().is_some_and(|c| c.version.is_some() && c.ref_.is_some()) {
+                bail!(Failure::Config(format!("{} sets both ref and version in plugins.toml, keep only one", key)));
+            }
+        }
+
+        let clashes: Vec<String> = names
+            .iter()
+            .filter(|(_, keys)| keys.len() > 1)
+            .map(|(name, keys)| format!("{} would share plugins/{}.so", keys.join(", "), name))
+            .collect();
+
+        if !clashes.is_empty() {
+            bail!(Failure::Config(format!(
+                "plugins.toml has plugins with the same name:\n  {}\ngive them different names with `name = \"...\"`",
+                clashes.join("\n  ")
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(untagged)]
 pub enum PluginEntry {
     Ref(String),
-    Config(PluginConfig),
+    Config(Box<PluginConfig>),
 }
 
-#[derive(Deserialize, Serialize)]
+impl PluginEntry {
+    pub fn config(&self) -> Option<&PluginConfig> {
+        match self {
+            PluginEntry::Config(c) => Some(c),
+            PluginEntry::Ref(_) => None,
+        }
+    }
+
+    pub fn ref_(&self) -> &str {
+        match self {
+            PluginEntry::Ref(r) => r.as_str(),
+            PluginEntry::Config(c) => c.ref_.as_deref().unwrap_or(DEFAULT_REF),
+        }
+    }
+
+    /// Semver requirement the ref is resolved from, `version` or a shorthand like `"^1.2"`
+    pub fn version_req(&self) -> Option<&str> {
+        match self {
+            PluginEntry::Ref(r) => Some(r.as_str()).filter(|r| versions::is_requirement(r)),
+            PluginEntry::Config(c) => c
+                .version
+                .as_deref()
+                .or_else(|| c.ref_.as_deref().filter(|r| versions::is_requirement(r))),
+        }
+    }
+
+    /// This entry with its version requirement resolved to `tag`.
+    pub fn resolved(&self, tag: &str) -> PluginEntry {
+        let mut config = match self {
+            PluginEntry::Config(c) => (**c).clone(),
+            PluginEntry::Ref(_) => PluginConfig::default(),
+        };
+        config.version = self.version_req().map(str::to_string);
+        config.ref_ = Some(tag.to_string());
+        PluginEntry::Config(Box::new(config))
+    }
+
+    /// Name the plugin is installed as (`plugins/<name>.so`), `name` or the repository name
+    pub fn name(&self, key: &str) -> Result<String> {
+        match self.config().and_then(|c| c.name.as_deref()) {
+            Some(name) => {
+                if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+                    bail!(Failure::Config(format!("invalid name '{}' for {}", name, key)));
+                }
+                Ok(name.to_string())
+            }
+            None => Ok(self.source(key)?.name().to_string()),
+        }
+    }
+
+    /// Resolve where the plugin comes from, `path` or `git` override the key if set
+    pub fn source(&self, key: &str) -> Result<PluginSource> {
+        match self {
+            PluginEntry::Config(c) => match (&c.path, &c.git) {
+                (Some(path), _) => Ok(PluginSource {
+                    kind: SourceKind::Path,
+                    location: path.clone(),
+                }),
+                (None, Some(url)) => PluginSource::parse(url),
+                (None, None) => PluginSource::parse(key),
+            },
+            PluginEntry::Ref(_) => PluginSource::parse(key),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct PluginConfig {
     #[serde(rename = "ref")]
-    pub ref_: String,
-    pub build: Option<String>,
+    pub ref_: Option<String>,
+    /// Semver requirement resolved to the highest matching tag, instead of `ref`
+    pub version: Option<String>,
+    pub git: Option<String>,
+    /// Local checkout to build from instead of fetching
+    pub path: Option<String>,
+    /// File name to install the plugin as, defaults to the repository name
+    pub name: Option<String>,
+    pub build: Option<BuildCommand>,
+    /// Environment variables set for the build command
+    pub env: Option<IndexMap<String, String>>,
+    /// Directory inside the source to run the build in
+    pub workdir: Option<String>,
+    /// Run a string build command through `sh -c`
+    pub shell: Option<bool>,
+    /// Build timeout in seconds, 0 disables it
+    pub timeout: Option<u64>,
     pub artifact: Option<String>,
-    pub prebuilt: Option<bool>,
+    /// Extra files to install, `"src"` or `"src -> dest"`, globs allowed
+    pub artifacts: Option<Vec<String>>,
+    pub prebuilt: Option<PrebuiltPolicy>,
+    /// Minisign public keys trusted to sign the plugin's prebuilt binaries
+    pub pubkey: Option<PublicKeys>,
+}
+
+/// Whether to install a plugin from its `[plugin.prebuilt]` binary.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase", try_from = "PrebuiltValue")]
+pub enum PrebuiltPolicy {
+    /// Download the prebuilt, building from source if there is none or it can't be downloaded
+    Prefer,
+    /// Download the prebuilt and fail if that isn't possible
+    Require,
+    /// Always build from source
+    #[default]
+    Never,
+}
+
+impl PrebuiltPolicy {
+    pub fn is_never(&self) -> bool {
+        *self == PrebuiltPolicy::Never
+    }
+}
+
+impl fmt::Display for PrebuiltPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PrebuiltPolicy::Prefer => "prefer",
+            PrebuiltPolicy::Require => "require",
+            PrebuiltPolicy::Never => "never",
+        })
+    }
+}
+
+/// `prebuilt = true` is the same as `"prefer"`, `false` as `"never"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PrebuiltValue {
+    Flag(bool),
+    Policy(String),
+}
+
+impl TryFrom<PrebuiltValue> for PrebuiltPolicy {
+    type Error = String;
+
+    fn try_from(value: PrebuiltValue) -> Result<Self, Self::Error> {
+        match value {
+            PrebuiltValue::Flag(true) => Ok(PrebuiltPolicy::Prefer),
+            PrebuiltValue::Flag(false) => Ok(PrebuiltPolicy::Never),
+            PrebuiltValue::Policy(policy) => match policy.as_str() {
+                "prefer" => Ok(PrebuiltPolicy::Prefer),
+                "require" => Ok(PrebuiltPolicy::Require),
+                "never" => Ok(PrebuiltPolicy::Never),
+                _ => Err(format!("invalid prebuilt policy '{}', expected \"prefer\", \"require\" or \"never\"", policy)),
+            },
+        }
+    }
+}
+
+/// How a plugin's artifact was installed.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallMethod {
+    #[default]
+    Build,
+    Prebuilt,
+}
+
+/// One trusted public key or a list of them.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum PublicKeys {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl PublicKeys {
+    pub fn keys(&self) -> &[String] {
+        match self {
+            PublicKeys::One(key) => std::slice::from_ref(key),
+            PublicKeys::Many(keys) => keys,
+        }
+    }
+}
+
+/// A build command, either a command line or an argv list that is run as is.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum BuildCommand {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl fmt::Display for BuildCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildCommand::Line(line) => f.write_str(line),
+            BuildCommand::Args(args) => f.write_str(&shell_words::join(args)),
+        }
+    }
 }
 
 // plugins.lock schema
@@ -33,14 +253,86 @@ pub struct LockFile {
     pub plugin: Vec<LockedPlugin>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct LockedPlugin {
+    /// Installed name, unique within the lockfile
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
     pub repo: String,
-    #[serde(rename = "ref")]
+    #[serde(rename = "ref", default, skip_serializing_if = "String::is_empty")]
     pub ref_: String,
+    /// Version requirement `ref` was resolved from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub sha: String,
+    /// `git` override the plugin was fetched from, instead of its key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<String>,
+    /// Set for plugins built from a local path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Local checkout had uncommitted changes when it was built
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dirty: bool,
     pub artifact: String,
     pub built_at: String,
+    /// Overrides from plugins.toml at install time, used to detect drift
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<BuildCommand>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<IndexMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workdir: Option<String>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub shell: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_override: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifacts: Option<Vec<String>>,
+    /// Extra files installed from `artifacts`, relative to the config dir
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    #[serde(default, skip_serializing_if = "PrebuiltPolicy::is_never")]
+    pub prebuilt: PrebuiltPolicy,
+    /// Whether the artifact was built or downloaded
+    #[serde(default)]
+    pub method: InstallMethod,
+    /// SHA-256 of the downloaded prebuilt
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<PublicKeys>,
+}
+
+impl LockedPlugin {
+    /// Installed name, older lockfiles only have it as the artifact's file name.
+    pub fn installed_name(&self) -> &str {
+        if !self.name.is_empty() {
+            return &self.name;
+        }
+        Path::new(&self.artifact).file_stem().and_then(|s| s.to_str()).unwrap_or(&self.repo)
+    }
+}
+
+// history.toml schema (under the cache)
+
+#[derive(Deserialize, Serialize, Default)]
+pub struct HistoryFile {
+    #[serde(default)]
+    pub entry: Vec<HistoryEntry>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct HistoryEntry {
+    /// Artifact copy, relative to the plugin's history dir
+    pub file: String,
+    #[serde(flatten)]
+    pub plugin: LockedPlugin,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 // plugin.toml schema
@@ -52,14 +344,47 @@ pub struct PluginManifest {
 
 #[derive(Deserialize)]
 pub struct PluginManifestInner {
-    pub build: Option<String>,
+    pub build: Option<BuildCommand>,
+    pub env: Option<IndexMap<String, String>>,
+    pub workdir: Option<String>,
+    pub shell: Option<bool>,
     pub artifact: Option<String>,
+    pub artifacts: Option<Vec<String>>,
     pub prebuilt: Option<PrebuiltConfig>,
 }
 
 #[derive(Deserialize)]
 pub struct PrebuiltConfig {
-    /// Direct URL to the binary, supports {version} and {arch} placeholders
+    /// Direct URL to the binary, or one per target. Supports {version}, {arch}, {os},
+    /// {libc}, {target}, {sha} and {name} placeholders
     /// e.g. "https://github.com/user/repo/releases/download/{version}/libwidget-{arch}.so"
-    pub url: String,
+    pub url: TargetSpec,
+    /// Expected SHA-256 of the download
+    pub sha256: Option<TargetSpec>,
+    /// SHA256SUMS style file listing the download, supports the same placeholders as `url`
+    pub checksums_url: Option<String>,
+    /// Detached minisign signature of the download, defaults to `<url>.minisig`
+    pub signature_url: Option<String>,
+    /// Format of the download if it is an archive, guessed from `url` when unset
+    pub archive: Option<ArchiveFormat>,
+    /// Path or glob of the binary inside the archive, defaults to the only `*.so`
+    pub member: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub enum ArchiveFormat {
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+/// One value for every target, or one per target.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum TargetSpec {
+    Single(String),
+    /// Keyed by target triple, `<arch>-<os>-<libc>` or `<arch>-<os>`,
+    /// e.g. "x86_64-unknown-linux-gnu", "x86_64-linux-musl" or "x86_64-linux"
+    PerTarget(IndexMap<String, String>),
 }
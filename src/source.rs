@@ -0,0 +1,225 @@
+//! Where a plugin is fetched from.
+//!
+//! Keys in plugins.toml (or the `git` override of an entry) can be:
+//! - `user/repo`              (GitHub, the default)
+//! - `github:user/repo`, `gitlab:user/repo`, `codeberg:user/repo`
+//!   (GitLab also takes nested groups, `gitlab:group/subgroup/repo`)
+//! - a full git URL such as `https://git.example.com/user/repo.git` or `git@host:user/repo`
+//! - a local repository as `file:///path/to/repo`
+//!
+//! Entries with a `path` key are built in place and never fetched.
+
+use anyhow::{bail, Result};
+use std::fmt;
+use crate::error::Failure;
+use crate::utils;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceKind {
+    GitHub,
+    GitLab,
+    Codeberg,
+    Git,
+    File,
+    Path,
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            SourceKind::GitHub => "github",
+            SourceKind::GitLab => "gitlab",
+            SourceKind::Codeberg => "codeberg",
+            SourceKind::Git => "git",
+            SourceKind::File => "file",
+            SourceKind::Path => "path",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginSource {
+    pub kind: SourceKind,
+    /// `user/repo` for hosted sources, the directory for path sources, the full URL otherwise
+    pub location: String,
+}
+
+impl PluginSource {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+
+        if let Some((prefix, path)) = spec.split_once(':') {
+            let kind = match prefix {
+                "github" => Some(SourceKind::GitHub),
+                "gitlab" => Some(SourceKind::GitLab),
+                "codeberg" => Some(SourceKind::Codeberg),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                return Self::hosted(kind, path, spec);
+            }
+        }
+
+        if spec.starts_with("file://") {
+            if spec.len() <= "file://".len() {
+                bail!(Failure::Config(format!("'{}' is missing a path", spec)));
+            }
+            return Ok(PluginSource { kind: SourceKind::File, location: spec.to_string() });
+        }
+
+        if spec.contains("://") || is_scp_like(spec) {
+            return Ok(PluginSource { kind: SourceKind::Git, location: spec.to_string() });
+        }
+
+        Self::hosted(SourceKind::GitHub, spec, spec)
+    }
+
+    fn hosted(kind: SourceKind, path: &str, spec: &str) -> Result<Self> {
+        let path = path.trim_matches('/').trim_end_matches(".git");
+        let parts: Vec<&str> = path.split('/').collect();
+        // GitLab projects can live in nested groups: `group/subgroup/repo`
+        let valid = match kind {
+            SourceKind::GitLab => parts.len() >= 2,
+            _ => parts.len() == 2,
+        };
+        if !valid || parts.iter().any(|p| p.is_empty()) {
+            bail!(Failure::Config(format!(
+                "'{}' is not a valid plugin source, expected \"user/repo\" or a full git URL such as \"https://host/group/repo.git\"",
+                spec
+            )));
+        }
+        Ok(PluginSource { kind, location: path.to_string() })
+    }
+
+    /// URL handed to git for fetching
+    pub fn url(&self) -> String {
+        match self.kind {
+            SourceKind::GitHub => format!("https://github.com/{}.git", self.location),
+            SourceKind::GitLab => format!("https://gitlab.com/{}.git", self.location),
+            SourceKind::Codeberg => format!("https://codeberg.org/{}.git", self.location),
+            SourceKind::Git | SourceKind::File | SourceKind::Path => self.location.clone(),
+        }
+    }
+
+    /// Repository name without owner or `.git` suffix, used for artifact names
+    pub fn name(&self) -> &str {
+        let trimmed = self.location.trim_end_matches('/').trim_end_matches(".git");
+        trimmed
+            .rsplit(['/', ':'])
+            .next()
+            .unwrap_or(trimmed)
+    }
+
+    /// Directory name used inside the eiipm cache
+    pub fn cache_key(&self) -> String {
+        match self.kind {
+            // kept identical to older eiipm versions so existing caches are reused
+            SourceKind::GitHub => self.location.replace('/', "__"),
+            _ => format!("{}__{}", self.kind, utils::dir_name(&self.location)),
+        }
+    }
+}
+
+impl fmt::Display for PluginSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            SourceKind::GitHub => f.write_str(&self.location),
+            SourceKind::GitLab | SourceKind::Codeberg => write!(f, "{}:{}", self.kind, self.location),
+            SourceKind::Git | SourceKind::File | SourceKind::Path => f.write_str(&self.location),
+        }
+    }
+}
+
+/// `git@host:user/repo` style remotes
+fn is_scp_like(spec: &str) -> bool {
+    match spec.split_once(':') {
+        Some((host, path)) => host.contains('@') && !path.is_empty() && !path.starts_with("//"),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(spec: &str) -> PluginSource {
+        PluginSource::parse(spec).unwrap()
+    }
+
+    #[test]
+    fn shorthand_is_github() {
+        let source = parse("user/repo");
+        assert_eq!(source.kind, SourceKind::GitHub);
+        assert_eq!(source.location, "user/repo");
+        assert_eq!(source.url(), "https://github.com/user/repo.git");
+        assert_eq!(source.name(), "repo");
+        assert_eq!(source.cache_key(), "user__repo");
+        assert_eq!(source.to_string(), "user/repo");
+    }
+
+    #[test]
+    fn hosted_prefixes() {
+        assert_eq!(parse("github:user/repo"), parse("user/repo"));
+
+        let gitlab = parse("gitlab:user/repo.git");
+        assert_eq!(gitlab.kind, SourceKind::GitLab);
+        assert_eq!(gitlab.location, "user/repo");
+        assert_eq!(gitlab.url(), "https://gitlab.com/user/repo.git");
+        assert_eq!(gitlab.to_string(), "gitlab:user/repo");
+        assert!(gitlab.cache_key().starts_with("gitlab__user_repo-"));
+
+        let nested = parse("gitlab:group/subgroup/repo");
+        assert_eq!(nested.location, "group/subgroup/repo");
+        assert_eq!(nested.url(), "https://gitlab.com/group/subgroup/repo.git");
+        assert_eq!(nested.name(), "repo");
+
+        let codeberg = parse("codeberg:/user/repo/");
+        assert_eq!(codeberg.kind, SourceKind::Codeberg);
+        assert_eq!(codeberg.url(), "https://codeberg.org/user/repo.git");
+    }
+
+    #[test]
+    fn git_urls() {
+        let https = parse("https://git.example.com/user/my-plugin.git");
+        assert_eq!(https.kind, SourceKind::Git);
+        assert_eq!(https.url(), "https://git.example.com/user/my-plugin.git");
+        assert_eq!(https.name(), "my-plugin");
+        assert!(https.cache_key().starts_with("git__https___git.example.com_user_my-plugin.git-"));
+
+        let scp = parse("git@example.com:user/repo.git");
+        assert_eq!(scp.kind, SourceKind::Git);
+        assert_eq!(scp.name(), "repo");
+
+        let ssh = parse("ssh://git@example.com/repo");
+        assert_eq!(ssh.kind, SourceKind::Git);
+        assert_eq!(ssh.name(), "repo");
+    }
+
+    #[test]
+    fn file_urls() {
+        let file = parse("file:///home/me/src/plugin");
+        assert_eq!(file.kind, SourceKind::File);
+        assert_eq!(file.url(), "file:///home/me/src/plugin");
+        assert_eq!(file.name(), "plugin");
+
+        assert!(PluginSource::parse("file://").is_err());
+    }
+
+    #[test]
+    fn invalid_shorthands() {
+        for spec in ["repo", "user/", "/repo", "a/b/c", "codeberg:a/b/c", "gitlab:repo", "gitlab:a//b", ""] {
+            let err = PluginSource::parse(spec).unwrap_err();
+            assert!(err.to_string().contains("is not a valid plugin source"), "{}: {}", spec, err);
+            assert!(err.to_string().contains("full git URL"), "{}: {}", spec, err);
+        }
+    }
+
+    #[test]
+    fn cache_keys_dont_collide() {
+        let a = parse("https://x.org/a_b/c");
+        let b = parse("https://x.org/a/b_c");
+        assert_ne!(a.cache_key(), b.cache_key());
+        assert_eq!(a.cache_key(), parse("https://x.org/a_b/c").cache_key());
+    }
+}
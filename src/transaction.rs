@@ -0,0 +1,311 @@
+//! All-or-nothing artifact installation for one `install`/`update` run.
+//!
+//! New artifacts are written to a hidden staging file next to their
+//! destination and renamed into place, so ewwii never sees a truncated `.so`.
+//! The artifact being replaced is kept as a hidden `.bak` hard link until the
+//! run commits. Writing plugins.lock is the commit point: if the run fails
+//! before it (or panics), dropping the [`Transaction`] puts every previous
+//! artifact back and removes the ones that didn't exist before.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct Transaction {
+    replaced: Mutex<Vec<Replaced>>,
+    committed: bool,
+}
+
+struct Replaced {
+    dst: PathBuf,
+    /// None if `dst` didn't exist before this run
+    backup: Option<PathBuf>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Transaction::default()
+    }
+
+    /// Atomically replace `dst` with whatever `write` puts at the staging path it's given.
+    pub fn install(&self, dst: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+        let staged = sibling(dst, &format!("{}.tmp", process::id()));
+
+        if let Err(e) = write(&staged) {
+            let _ = fs::remove_file(&staged);
+            return Err(e);
+        }
+
+        let mut replaced = self.replaced.lock().unwrap_or_else(|e| e.into_inner());
+
+        // only the state before the run matters if a plugin is installed twice
+        if !replaced.iter().any(|r| r.dst == dst) {
+            let backup = if dst.exists() {
+                let backup = sibling(dst, "bak");
+                let _ = fs::remove_file(&backup);
+                let linked = fs::hard_link(dst, &backup).or_else(|_| fs::copy(dst, &backup).map(|_| ()));
+                if let Err(e) = linked {
+                    let _ = fs::remove_file(&staged);
+                    return Err(e).with_context(|| format!("failed to back up {}", dst.display()));
+                }
+                Some(backup)
+            } else {
+                None
+            };
+            replaced.push(Replaced { dst: dst.to_path_buf(), backup });
+        }
+
+        if let Err(e) = fs::rename(&staged, dst) {
+            let _ = fs::remove_file(&staged);
+            return Err(e).with_context(|| format!("failed to move artifact into {}", dst.display()));
+        }
+        Ok(())
+    }
+
+    /// Backup of what `dst` was before this run, if it was replaced.
+    pub fn backup_of(&self, dst: &Path) -> Option<PathBuf> {
+        let replaced = self.replaced.lock().unwrap_or_else(|e| e.into_inner());
+        replaced.iter().find(|r| r.dst == dst).and_then(|r| r.backup.clone())
+    }
+
+    /// Put `dsts` back the way they were before this run, e.g. when a plugin
+    /// fails halfway through installing its files. The rest is left as is.
+    pub fn restore(&self, dsts: &[PathBuf]) {
+        let mut replaced = self.replaced.lock().unwrap_or_else(|e| e.into_inner());
+        let (restored, kept): (Vec<Replaced>, Vec<Replaced>) = replaced.drain(..).partition(|r| dsts.contains(&r.dst));
+        *replaced = kept;
+        for r in restored.into_iter().rev() {
+            r.restore();
+        }
+    }
+
+    /// Keep everything installed so far and drop the backups.
+    pub fn commit(mut self) {
+        self.committed = true;
+        for r in self.replaced.get_mut().unwrap_or_else(|e| e.into_inner()).drain(..) {
+            if let Some(backup) = r.backup {
+                let _ = fs::remove_file(backup);
+            }
+        }
+    }
+
+    fn rollback(&mut self) {
+        let replaced = self.replaced.get_mut().unwrap_or_else(|e| e.into_inner());
+        if replaced.is_empty() {
+            return;
+        }
+
+        log::warn!(
+            "restoring the previous state of {} artifact{}",
+            replaced.len(),
+            if replaced.len() == 1 { "" } else { "s" }
+        );
+        for r in replaced.drain(..).rev() {
+            r.restore();
+        }
+    }
+}
+
+impl Replaced {
+    fn restore(self) {
+        let result = match &self.backup {
+            Some(backup) => fs::rename(backup, &self.dst),
+            None => fs::remove_file(&self.dst),
+        };
+        if let Err(e) = result {
+            log::error!("failed to restore {}: {}", self.dst.display(), e);
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.rollback();
+        }
+    }
+}
+
+/// Write `contents` to `path` through a staging file, so readers see either the old or the new file.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let staged = sibling(path, &format!("{}.tmp", process::id()));
+
+    if let Err(e) = fs::write(&staged, contents).and_then(|_| fs::rename(&staged, path)) {
+        let _ = fs::remove_file(&staged);
+        return Err(e).with_context(|| format!("failed to write {}", path.display()));
+    }
+    Ok(())
+}
+
+/// Hidden file next to `path`, e.g. `plugins/.foo.so.bak`.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Staging callback that writes `contents`.
+    fn write(contents: &str) -> impl FnOnce(&Path) -> Result<()> {
+        let contents = contents.to_string();
+        move |staged| Ok(fs::write(staged, contents)?)
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    /// Names in `dir`, sorted, to catch leftover staging and backup files.
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn drop_restores_replaced_file() {
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("a.so");
+        fs::write(&dst, "old").unwrap();
+
+        let tx = Transaction::new();
+        tx.install(&dst, write("new")).unwrap();
+        assert_eq!(read(&dst), "new");
+        assert_eq!(read(&tx.backup_of(&dst).unwrap()), "old");
+        drop(tx);
+
+        assert_eq!(read(&dst), "old");
+        assert_eq!(names(dir.path()), ["a.so"]);
+    }
+
+    #[test]
+    fn drop_removes_new_file() {
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("a.so");
+
+        let tx = Transaction::new();
+        tx.install(&dst, write("new")).unwrap();
+        assert_eq!(tx.backup_of(&dst), None);
+        drop(tx);
+
+        assert!(names(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn installing_twice_keeps_first_backup() {
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("a.so");
+        fs::write(&dst, "old").unwrap();
+
+        let tx = Transaction::new();
+        tx.install(&dst, write("first")).unwrap();
+        tx.install(&dst, write("second")).unwrap();
+        assert_eq!(read(&dst), "second");
+        assert_eq!(read(&tx.backup_of(&dst).unwrap()), "old");
+        drop(tx);
+
+        assert_eq!(read(&dst), "old");
+    }
+
+    #[test]
+    fn commit_keeps_new_files_and_removes_backups() {
+        let dir = TempDir::new().unwrap();
+        let a = dir.path().join("a.so");
+        let b = dir.path().join("b.so");
+        fs::write(&a, "old").unwrap();
+
+        let tx = Transaction::new();
+        tx.install(&a, write("new a")).unwrap();
+        tx.install(&b, write("new b")).unwrap();
+        tx.commit();
+
+        assert_eq!(read(&a), "new a");
+        assert_eq!(read(&b), "new b");
+        assert_eq!(names(dir.path()), ["a.so", "b.so"]);
+    }
+
+    #[test]
+    fn failed_write_leaves_nothing_behind() {
+        let dir = TempDir::new().unwrap();
+        let dst = dir.path().join("a.so");
+        fs::write(&dst, "old").unwrap();
+
+        let tx = Transaction::new();
+        let result = tx.install(&dst, |staged| {
+            fs::write(staged, "partial")?;
+            anyhow::bail!("build failed")
+        });
+        assert!(result.is_err());
+        assert_eq!(tx.backup_of(&dst), None);
+        drop(tx);
+
+        assert_eq!(read(&dst), "old");
+        assert_eq!(names(dir.path()), ["a.so"]);
+    }
+
+    #[test]
+    fn failed_backup_leaves_nothing_behind() {
+        let dir = TempDir::new().unwrap();
+        // a directory can be neither hard linked nor copied
+        let dst = dir.path().join("a.so");
+        fs::create_dir(&dst).unwrap();
+
+        let tx = Transaction::new();
+        assert!(tx.install(&dst, write("new")).is_err());
+        drop(tx);
+
+        assert!(dst.is_dir());
+        assert_eq!(names(dir.path()), ["a.so"]);
+    }
+
+    #[test]
+    fn restore_only_touches_given_files() {
+        let dir = TempDir::new().unwrap();
+        let a = dir.path().join("a.so");
+        let b = dir.path().join("b.so");
+        fs::write(&a, "old a").unwrap();
+        fs::write(&b, "old b").unwrap();
+
+        let tx = Transaction::new();
+        tx.install(&a, write("new a")).unwrap();
+        tx.install(&b, write("new b")).unwrap();
+        tx.restore(std::slice::from_ref(&a));
+        tx.commit();
+
+        assert_eq!(read(&a), "old a");
+        assert_eq!(read(&b), "new b");
+        assert_eq!(names(dir.path()), ["a.so", "b.so"]);
+    }
+
+    #[test]
+    fn write_atomic_replaces_contents() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("plugins.lock");
+        fs::write(&path, "old contents that are longer").unwrap();
+
+        write_atomic(&path, "new").unwrap();
+
+        assert_eq!(read(&path), "new");
+        assert_eq!(names(dir.path()), ["plugins.lock"]);
+    }
+
+    #[test]
+    fn write_atomic_failure_cleans_up() {
+        let dir = TempDir::new().unwrap();
+        // renaming a file over a directory fails
+        let path = dir.path().join("plugins.lock");
+        fs::create_dir(&path).unwrap();
+
+        assert!(write_atomic(&path, "new").is_err());
+        assert_eq!(names(dir.path()), ["plugins.lock"]);
+    }
+}
@@ -1,10 +1,107 @@
-use std::io::{self, Write};
+use crate::cancel;
+use crate::error::Failure;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How confirmation prompts are answered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Assume {
+    Ask,
+    Yes,
+    No,
+}
+
+static ASSUME: OnceLock<Assume> = OnceLock::new();
+
+pub fn set_assume(assume: Assume) {
+    let _ = ASSUME.set(assume);
+}
+
+pub fn confirm(prompt: &str) -> Result<bool> {
+    match ASSUME.get().copied().unwrap_or(Assume::Ask) {
+        Assume::Yes => {
+            log::info!("{} [y/N]: y (assumed)", prompt);
+            return Ok(true);
+        }
+        Assume::No => {
+            log::info!("{} [y/N]: n (assumed)", prompt);
+            return Ok(false);
+        }
+        Assume::Ask => {}
+    }
+
+    // exits like a declined prompt, so scripts can tell it from a real failure
+    if !io::stdin().is_terminal() {
+        log::error!("cannot ask '{}' because stdin is not a terminal, pass --yes or --no", prompt);
+        bail!(Failure::Aborted);
+    }
 
-pub fn confirm(prompt: &str) -> bool {
-    let mut input = String::new();
     print!("{} [y/N]: ", prompt);
-    io::stdout().flush().unwrap();
+    io::stdout().flush().context("failed to flush stdout")?;
+
+    // read on another thread so a Ctrl-C caught by our handler ends the prompt
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = String::new();
+        let _ = tx.send(io::stdin().read_line(&mut input).map(|_| input));
+    });
+
+    loop {
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(input) => {
+                let input = input.context("failed to read answer from stdin")?;
+                return Ok(matches!(input.trim().to_lowercase().as_str(), "y" | "yes"));
+            }
+            Err(RecvTimeoutError::Timeout) if cancel::requested() => {
+                println!();
+                bail!(Failure::Interrupted);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("failed to read answer from stdin"),
+        }
+    }
+}
+
+/// Most recent modification time of any file under `dir`,
+/// skipping hidden entries and cargo's `target/`.
+pub fn newest_mtime(dir: &Path) -> Option<SystemTime> {
+    let mut newest = None;
+
+    for entry in fs::read_dir(dir).ok()?.filter_map(|e| e.ok()) {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with('.') || name == "target" {
+            continue;
+        }
+
+        let Ok(file_type) = entry.file_type() else { continue };
+        let mtime = if file_type.is_dir() {
+            newest_mtime(&entry.path())
+        } else {
+            entry.metadata().and_then(|m| m.modified()).ok()
+        };
+
+        newest = newest.max(mtime);
+    }
+
+    newest
+}
 
-    io::stdin().read_line(&mut input).unwrap();
-    matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
+/// A directory name for `text` that is safe on any filesystem. Characters other
+/// than ASCII letters, digits, `-` and `.` become `_`, and a short hash of `text`
+/// keeps apart names that only differ in those, like `a_b/c` and `a/b_c`.
+pub fn dir_name(text: &str) -> String {
+    let sanitized: String = text
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    let hash: String = Sha256::digest(text.as_bytes())[..4].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", sanitized, hash)
 }
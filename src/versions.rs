@@ -0,0 +1,121 @@
+//! Semver requirements in plugins.toml (`"^1.2"` or `{ version = "~0.4" }`).
+//!
+//! A requirement is resolved against the tags of the plugin's remote, listed
+//! without fetching anything. Tags are read as versions with an optional
+//! leading `v` and the highest one matching wins. Like Cargo, pre-releases
+//! only match requirements that mention a pre-release themselves.
+
+use crate::error::Failure;
+use crate::git;
+use anyhow::{bail, Context, Result};
+use semver::{Version, VersionReq};
+
+/// Whether a shorthand ref is a version requirement rather than a branch, tag or commit.
+pub fn is_requirement(ref_: &str) -> bool {
+    ref_.starts_with(['^', '~', '=', '<', '>', '*'])
+}
+
+pub fn parse(req: &str) -> Result<VersionReq> {
+    let parsed = VersionReq::parse(req)
+        .map_err(|e| Failure::Config(format!("invalid version requirement '{}': {}", req, e)))?;
+    Ok(parsed)
+}
+
+/// The highest tag of `repo_url` matching `req`.
+pub fn resolve(repo_url: &str, req: &str) -> Result<String> {
+    let tags = git::remote_tags(repo_url).with_context(|| format!("failed to list tags of {}", repo_url))?;
+    Ok(pick(req, &tags)?.to_string())
+}
+
+/// The highest of `tags` matching `req`.
+pub fn pick<'a>(req: &str, tags: &'a [String]) -> Result<&'a str> {
+    let parsed = parse(req)?;
+
+    if let Some((_, tag)) = versioned(tags).filter(|(version, _)| parsed.matches(version)).max() {
+        return Ok(tag);
+    }
+    match latest(tags) {
+        Some(latest) => bail!(Failure::Config(format!("no tag matches {} (latest is {})", req, latest))),
+        None => bail!(Failure::Config(format!("no tag matches {}, the repository has no version tags", req))),
+    }
+}
+
+/// The tag with the highest version, preferring releases over pre-releases.
+pub fn latest(tags: &[String]) -> Option<&str> {
+    let release = versioned(tags).filter(|(version, _)| version.pre.is_empty()).max();
+    release.or_else(|| versioned(tags).max()).map(|(_, tag)| tag)
+}
+
+/// `tag` as a version, e.g. `v1.2.0` or `1.2.0`.
+pub fn tag_version(tag: &str) -> Option<Version> {
+    Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+}
+
+fn versioned(tags: &[String]) -> impl Iterator<Item = (Version, &str)> {
+    tags.iter().filter_map(|tag| Some((tag_version(tag)?, tag.as_str())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn requirements_are_told_apart_from_refs() {
+        for req in ["^1.2", "~0.4", "=1.0.0", ">=1, <2", "*"] {
+            assert!(is_requirement(req), "{}", req);
+        }
+        for ref_ in ["main", "v1.2.0", "1.2.0", "a1b2c3d"] {
+            assert!(!is_requirement(ref_), "{}", ref_);
+        }
+    }
+
+    #[test]
+    fn pick_takes_the_highest_match() {
+        let tags = tags(&["v1.0.0", "v1.2.0", "v1.10.1", "v2.0.0", "nightly", "1.3.0"]);
+        assert_eq!(pick("^1.2", &tags).unwrap(), "v1.10.1");
+        assert_eq!(pick("~1.2", &tags).unwrap(), "v1.2.0");
+        assert_eq!(pick("=1.3.0", &tags).unwrap(), "1.3.0");
+        assert_eq!(pick("*", &tags).unwrap(), "v2.0.0");
+    }
+
+    #[test]
+    fn pick_skips_pre_releases_unless_asked_for() {
+        let tags = tags(&["v1.0.0", "v1.1.0-beta.1", "v2.0.0-rc.1"]);
+        assert_eq!(pick("^1", &tags).unwrap(), "v1.0.0");
+        assert_eq!(pick("^1.1.0-beta", &tags).unwrap(), "v1.1.0-beta.1");
+        assert_eq!(pick(">=2.0.0-rc.1", &tags).unwrap(), "v2.0.0-rc.1");
+    }
+
+    #[test]
+    fn pick_errors() {
+        let err = pick("^3", &tags(&["v1.0.0", "v2.1.0"])).unwrap_err();
+        assert_eq!(err.to_string(), "no tag matches ^3 (latest is v2.1.0)");
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_CONFIG);
+
+        let err = pick("^1", &tags(&["nightly", "stable"])).unwrap_err();
+        assert_eq!(err.to_string(), "no tag matches ^1, the repository has no version tags");
+
+        let err = pick("not a version", &tags(&["v1.0.0"])).unwrap_err();
+        assert!(err.to_string().starts_with("invalid version requirement 'not a version'"), "{}", err);
+    }
+
+    #[test]
+    fn latest_prefers_releases() {
+        assert_eq!(latest(&tags(&["v1.0.0", "v1.1.0", "v2.0.0-rc.1", "main"])), Some("v1.1.0"));
+        assert_eq!(latest(&tags(&["v2.0.0-rc.1", "v2.0.0-rc.2"])), Some("v2.0.0-rc.2"));
+        assert_eq!(latest(&tags(&["main", "nightly"])), None);
+        assert_eq!(latest(&[]), None);
+    }
+
+    #[test]
+    fn tag_versions() {
+        assert_eq!(tag_version("v1.2.3"), Some(Version::new(1, 2, 3)));
+        assert_eq!(tag_version("1.2.3"), Some(Version::new(1, 2, 3)));
+        assert_eq!(tag_version("v1.2"), None);
+        assert_eq!(tag_version("release-1.2.3"), None);
+    }
+}